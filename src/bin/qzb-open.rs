@@ -0,0 +1,65 @@
+// opens a book in zathura once the client fetched it,
+// meant as the .desktop handler / xdg-open target for
+// books in the disposable vm, see qzb-open.desktop.
+//
+// usage: qzb-open <book> [zathura args...]
+//
+// book is a file below conf.book_dir or a path relative
+// to it, the client resolves it like any OPENED on the
+// notify socket, see notify.rs.
+#![allow(clippy::needless_return)]
+
+use qubes_zathura_bookmark::{
+    shared_consts::*,
+    notify::NotifyClient,
+};
+use std::{
+    env,
+    path::{self, Path},
+    process::{Command, ExitCode},
+    os::unix::process::CommandExt,
+    time::Duration,
+};
+
+const ZATHURA: &str = "zathura";
+/// a large book over a slow qrexec pipe takes a while
+const FETCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let Some(book) = args.next() else {
+        eprintln!("usage: qzb-open <book> [zathura args...]");
+        return ExitCode::FAILURE;
+    };
+
+    let path = match open(&book) {
+        Ok(path) => path,
+        Err(e) => {
+            let msg = format!("can't open {book}: {e}");
+            eprintln!("{msg}");
+            let _ = Command::new("notify-send")
+                .args(["zathura-bookmark", &msg])
+                .status();
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // only returns if zathura couldn't be started
+    let e = Command::new(ZATHURA).args(args).arg(path).exec();
+    eprintln!("can't run {ZATHURA}: {e}");
+    return ExitCode::FAILURE;
+}
+
+fn open(book: &str) -> DRes<String> {
+    // xdg-open hands over whatever path it was given,
+    // the client only strips an absolute book_dir prefix.
+    let book = if Path::new(book).exists() {
+        path::absolute(book)?.to_string_lossy().into_owned()
+    } else {
+        book.to_owned()
+    };
+
+    let mut client = NotifyClient::connect(CLIENT_ZATH_SOCK_PATH, FETCH_TIMEOUT)?;
+    let path = client.open(&book)?;
+    return Ok(path.to_string_lossy().into_owned());
+}
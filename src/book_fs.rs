@@ -0,0 +1,491 @@
+// the optional fuse mode of the client, book_dir becomes
+// a read only mount of the servers book index instead of
+// a directory of placeholders. Any program opening a book
+// blocks until the session loop fetched it into
+// conf.fuse_cache, no zathura patch needed. stat sees the
+// real size and mtime from the listing all along.
+//
+// the filesystem runs on its own thread, opens are handed
+// to the session loop through a FetchQueue since only the
+// session owns the qrexec pipe. open_watch.rs uses the
+// same channel.
+#![cfg_attr(not(feature = "fuse"), allow(dead_code))]
+
+use crate::{
+    shared_consts::*,
+    error::{QzbError, ErrorCode},
+    book_index::{BookEntry, BookIndex},
+    conf::Conf,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, mpsc},
+    time::Duration,
+};
+
+pub const ROOT_INO: u64 = 1;
+
+/// how long an open waits for the session to fetch the
+/// book, i.e. while the client reconnects.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// the directory tree of a book index with inode numbers.
+/// A path keeps its inode across listings so the kernel
+/// never sees one inode turn into another book, inodes of
+/// books that went away just stop resolving.
+#[derive(Debug)]
+pub struct BookTree {
+    nodes: BTreeMap<u64, Node>,
+    inos: HashMap<PathBuf, u64>,
+    next_ino: u64,
+}
+
+#[derive(Debug)]
+pub struct Node {
+    pub name: OsString,
+    pub parent: u64,
+    pub kind: NodeKind,
+}
+
+#[derive(Debug)]
+pub enum NodeKind {
+    /// the inodes of its entries, sorted by name
+    Dir(Vec<u64>),
+    Book(BookEntry),
+}
+
+impl BookTree {
+    pub fn new() -> Self {
+        let mut tree = Self {
+            nodes: BTreeMap::new(),
+            inos: HashMap::from([(PathBuf::new(), ROOT_INO)]),
+            next_ino: ROOT_INO + 1,
+        };
+        tree.update(&BookIndex::default());
+        return tree;
+    }
+
+    /// rebuilds the tree from a new listing.
+    pub fn update(&mut self, index: &BookIndex) {
+        self.nodes.clear();
+        let _ = self.nodes.insert(ROOT_INO, Node {
+            name: OsString::new(),
+            parent: ROOT_INO,
+            kind: NodeKind::Dir(vec!()),
+        });
+
+        for entry in &index.entries {
+            let mut parent = ROOT_INO;
+            let mut path = PathBuf::new();
+            let mut comps = entry.path.iter().peekable();
+            while let Some(name) = comps.next() {
+                path.push(name);
+                let kind = match comps.peek() {
+                    Some(_) => NodeKind::Dir(vec!()),
+                    None => NodeKind::Book(entry.clone()),
+                };
+                parent = self.insert(parent, &path, name, kind);
+            }
+        }
+
+        // the index is sorted by path, a directory can
+        // still come after its siblings, i.e. "a/b" and "a.pdf".
+        let names: HashMap<u64, OsString> = self.nodes.iter()
+            .map(|(ino, node)| (*ino, node.name.clone()))
+            .collect();
+        for node in self.nodes.values_mut() {
+            if let NodeKind::Dir(children) = &mut node.kind {
+                children.sort_by(|a, b| names[a].cmp(&names[b]));
+            }
+        }
+    }
+
+    /// adds the node at path unless it's already there,
+    /// returns its inode.
+    fn insert(&mut self, parent: u64, path: &Path, name: &OsStr, kind: NodeKind) -> u64 {
+        let ino = match self.inos.get(path) {
+            Some(ino) => *ino,
+            None => {
+                let ino = self.next_ino;
+                self.next_ino += 1;
+                let _ = self.inos.insert(path.to_owned(), ino);
+                ino
+            }
+        };
+
+        if self.nodes.contains_key(&ino) {
+            return ino;
+        }
+
+        let _ = self.nodes.insert(ino, Node { name: name.to_owned(), parent, kind });
+        if let Some(NodeKind::Dir(children)) = self.nodes.get_mut(&parent).map(|node| &mut node.kind) {
+            children.push(ino);
+        }
+        return ino;
+    }
+
+    pub fn get(&self, ino: u64) -> Option<&Node> {
+        return self.nodes.get(&ino);
+    }
+
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        let NodeKind::Dir(children) = &self.get(parent)?.kind else {
+            return None;
+        };
+
+        return children.iter()
+            .find(|ino| self.nodes.get(ino).is_some_and(|node| node.name == name))
+            .copied();
+    }
+}
+
+/// an open waiting for its book, done gets the errno
+/// the open fails with if the fetch did.
+pub struct Fetch {
+    pub path: PathBuf,
+    pub done: mpsc::Sender<Result<(), i32>>,
+}
+
+/// the filesystem end of the fetch channel, every fetch
+/// also bumps the eventfd the session loop waits on.
+#[derive(Clone)]
+pub struct Fetcher(mpsc::Sender<Fetch>, Arc<OwnedFd>);
+
+impl Fetcher {
+    /// blocks until the session fetched the book at path.
+    pub fn fetch(&self, path: &Path) -> Result<(), i32> {
+        return self.request(path)?
+            .recv_timeout(FETCH_TIMEOUT)
+            .map_err(|_| libc::ETIMEDOUT)?;
+    }
+
+    /// queues a fetch of the book at path without waiting
+    /// for it, the receiver gets the same as fetch returns.
+    pub fn request(&self, path: &Path) -> Result<mpsc::Receiver<Result<(), i32>>, i32> {
+        let (done, res) = mpsc::channel();
+        self.0.send(Fetch { path: path.to_owned(), done })
+            .map_err(|_| libc::EIO)?;
+
+        let one = 1u64.to_ne_bytes();
+        // only fails if the counter is about to overflow,
+        // the session is awake in that case anyway.
+        let _ = unsafe { libc::write(self.1.as_raw_fd(), one.as_ptr().cast(), one.len()) };
+        return Ok(res);
+    }
+}
+
+/// the session end of the fetch channel.
+pub struct FetchQueue(mpsc::Receiver<Fetch>, Arc<OwnedFd>);
+
+impl FetchQueue {
+    /// the fetches waiting right now, never blocks.
+    pub fn pending(&self) -> Vec<Fetch> {
+        // reset the eventfd before draining, a fetch queued
+        // in between bumps it again.
+        let mut count = [0u8; 8];
+        let _ = unsafe { libc::read(self.1.as_raw_fd(), count.as_mut_ptr().cast(), count.len()) };
+        return self.0.try_iter().collect();
+    }
+
+    /// readable while fetches are waiting.
+    pub fn fd(&self) -> RawFd {
+        return self.1.as_raw_fd();
+    }
+}
+
+pub fn fetch_channel() -> io::Result<(Fetcher, FetchQueue)> {
+    let raw = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if raw < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let event_fd = Arc::new(unsafe { OwnedFd::from_raw_fd(raw) });
+    let (tx, rx) = mpsc::channel();
+    return Ok((Fetcher(tx, event_fd.clone()), FetchQueue(rx, event_fd)));
+}
+
+/// what a failed fetch looks like to the program that
+/// opened the book.
+pub fn errno(e: &QzbError) -> i32 {
+    return match e {
+        QzbError::BookUnavailable
+        | QzbError::AmbiguousBook { .. }
+        | QzbError::Remote { code: ErrorCode::BookNotFound, .. } => libc::ENOENT,
+        QzbError::Denied(_)
+        | QzbError::Remote { code: ErrorCode::PermissionDenied, .. } => libc::EACCES,
+        QzbError::LimitExceeded { .. }
+        | QzbError::Remote { code: ErrorCode::LimitExceeded, .. } => libc::EFBIG,
+        _ => libc::EIO,
+    };
+}
+
+/// book_dir mounted for as long as this lives.
+pub struct BookMount {
+    pub tree: Arc<Mutex<BookTree>>,
+    #[cfg(feature = "fuse")]
+    _session: fuser::BackgroundSession,
+}
+
+impl BookMount {
+    #[cfg(feature = "fuse")]
+    pub fn mount(conf: &Conf, cache: &str, fetcher: Fetcher) -> DRes<Self> {
+        let tree = Arc::new(Mutex::new(BookTree::new()));
+        let fs = fuse::BookFs::new(tree.clone(), fetcher, Path::new(cache));
+        let options = [
+            fuser::MountOption::RO,
+            fuser::MountOption::NoExec,
+            fuser::MountOption::FSName("qubes-zathura-bookmark".to_owned()),
+        ];
+        let _session = fuser::spawn_mount2(fs, &conf.book_dir, &options)?;
+        return Ok(Self { tree, _session });
+    }
+
+    #[cfg(not(feature = "fuse"))]
+    pub fn mount(_: &Conf, _: &str, _: Fetcher) -> DRes<Self> {
+        return Err(QzbError::Config(
+            "fuse_cache is set but this build lacks the fuse feature".to_owned()));
+    }
+
+    pub fn set_index(&self, index: &BookIndex) -> DRes<()> {
+        self.tree.lock()
+            .map_err(|_| QzbError::Internal("the book tree lock is poisoned"))?
+            .update(index);
+        return Ok(());
+    }
+}
+
+#[cfg(feature = "fuse")]
+mod fuse {
+    use super::{BookTree, Fetcher, NodeKind};
+    use std::{
+        collections::HashMap,
+        ffi::OsStr,
+        fs::File,
+        os::unix::fs::FileExt,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex, MutexGuard, atomic::{AtomicU64, Ordering}},
+        thread,
+        time::{Duration, UNIX_EPOCH},
+    };
+    use fuser::{
+        FileAttr, FileType, Filesystem, ReplyAttr, ReplyData,
+        ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, Request,
+    };
+
+    /// how long the kernel may cache attributes and
+    /// lookups, short since a new listing can change them.
+    const TTL: Duration = Duration::from_secs(1);
+
+    pub struct BookFs {
+        tree: Arc<Mutex<BookTree>>,
+        fetcher: Fetcher,
+        cache: PathBuf,
+        // open books by file handle, shared with the
+        // threads waiting on a fetch, see open.
+        files: Arc<Mutex<HashMap<u64, Arc<File>>>>,
+        next_fh: Arc<AtomicU64>,
+        uid: u32,
+        gid: u32,
+    }
+
+    impl BookFs {
+        pub fn new(tree: Arc<Mutex<BookTree>>, fetcher: Fetcher, cache: &Path) -> Self {
+            // SAFETY: neither call can fail
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            return Self {
+                tree,
+                fetcher,
+                cache: cache.to_owned(),
+                files: Arc::new(Mutex::new(HashMap::new())),
+                next_fh: Arc::new(AtomicU64::new(1)),
+                uid,
+                gid,
+            };
+        }
+
+        fn tree(&self) -> MutexGuard<'_, BookTree> {
+            // the tree is only ever replaced whole
+            return self.tree.lock().unwrap_or_else(|e| e.into_inner());
+        }
+
+        fn files(&self) -> MutexGuard<'_, HashMap<u64, Arc<File>>> {
+            // only ever inserted into or removed from
+            return self.files.lock().unwrap_or_else(|e| e.into_inner());
+        }
+
+        fn attr(&self, tree: &BookTree, ino: u64) -> Option<FileAttr> {
+            let (kind, perm, size, mtime) = match &tree.get(ino)?.kind {
+                NodeKind::Dir(_) => (FileType::Directory, 0o555, 0, UNIX_EPOCH),
+                NodeKind::Book(entry) => {
+                    let secs = u64::try_from(entry.mtime).unwrap_or(0);
+                    (FileType::RegularFile, 0o444, entry.size,
+                        UNIX_EPOCH + Duration::from_secs(secs))
+                }
+            };
+
+            return Some(FileAttr {
+                ino,
+                size,
+                blocks: size.div_ceil(512),
+                atime: mtime,
+                mtime,
+                ctime: mtime,
+                crtime: mtime,
+                kind,
+                perm,
+                nlink: 1,
+                uid: self.uid,
+                gid: self.gid,
+                rdev: 0,
+                blksize: 4096,
+                flags: 0,
+            });
+        }
+    }
+
+    impl Filesystem for BookFs {
+        fn lookup(&mut self, _: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            let tree = self.tree();
+            match tree.lookup(parent, name).and_then(|ino| self.attr(&tree, ino)) {
+                Some(attr) => reply.entry(&TTL, &attr, 0),
+                None => reply.error(libc::ENOENT),
+            }
+        }
+
+        fn getattr(&mut self, _: &Request<'_>, ino: u64, _: Option<u64>, reply: ReplyAttr) {
+            let tree = self.tree();
+            match self.attr(&tree, ino) {
+                Some(attr) => reply.attr(&TTL, &attr),
+                None => reply.error(libc::ENOENT),
+            }
+        }
+
+        /// fetches the book unless the cache already has
+        /// the listed version, see Requester::get_book. The
+        /// session is single threaded so waiting for the fetch
+        /// here would stall every other request on the mount,
+        /// the reply comes from a thread of its own instead.
+        fn open(&mut self, _: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                return reply.error(libc::EROFS);
+            }
+
+            let path = match self.tree().get(ino).map(|node| &node.kind) {
+                Some(NodeKind::Book(entry)) => entry.path.clone(),
+                Some(NodeKind::Dir(_)) => return reply.error(libc::EISDIR),
+                None => return reply.error(libc::ENOENT),
+            };
+
+            let (fetcher, files, next_fh) =
+                (self.fetcher.clone(), self.files.clone(), self.next_fh.clone());
+            let book = self.cache.join(&path);
+            let _ = thread::spawn(move || {
+                if let Err(errno) = fetcher.fetch(&path) {
+                    return reply.error(errno);
+                }
+
+                match File::open(book) {
+                    Ok(file) => {
+                        let fh = next_fh.fetch_add(1, Ordering::Relaxed);
+                        let _ = files.lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .insert(fh, Arc::new(file));
+                        reply.opened(fh, 0);
+                    }
+                    Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+                }
+            });
+        }
+
+        fn read(
+            &mut self,
+            _: &Request<'_>,
+            _: u64,
+            fh: u64,
+            offset: i64,
+            size: u32,
+            _: i32,
+            _: Option<u64>,
+            reply: ReplyData,
+        ) {
+            let file = self.files().get(&fh).cloned();
+            let (Some(file), Ok(offset)) = (file, u64::try_from(offset)) else {
+                return reply.error(libc::EBADF);
+            };
+
+            let mut buf = vec!(0; size as usize);
+            let mut len = 0;
+            // read_at may stop short of the end of the file
+            while len < buf.len() {
+                match file.read_at(&mut buf[len..], offset + len as u64) {
+                    Ok(0) => break,
+                    Ok(nb) => len += nb,
+                    Err(e) => return reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+                }
+            }
+            reply.data(&buf[..len]);
+        }
+
+        fn release(
+            &mut self,
+            _: &Request<'_>,
+            _: u64,
+            fh: u64,
+            _: i32,
+            _: Option<u64>,
+            _: bool,
+            reply: ReplyEmpty,
+        ) {
+            let _ = self.files().remove(&fh);
+            reply.ok();
+        }
+
+        fn readdir(
+            &mut self,
+            _: &Request<'_>,
+            ino: u64,
+            _: u64,
+            offset: i64,
+            mut reply: ReplyDirectory,
+        ) {
+            let tree = self.tree();
+            let Some(node) = tree.get(ino) else {
+                return reply.error(libc::ENOENT);
+            };
+            let NodeKind::Dir(children) = &node.kind else {
+                return reply.error(libc::ENOTDIR);
+            };
+
+            let mut entries = vec!(
+                (ino, FileType::Directory, OsStr::new(".")),
+                (node.parent, FileType::Directory, OsStr::new("..")),
+            );
+            for child in children {
+                let Some(child_node) = tree.get(*child) else { continue };
+                let kind = match child_node.kind {
+                    NodeKind::Dir(_) => FileType::Directory,
+                    NodeKind::Book(_) => FileType::RegularFile,
+                };
+                entries.push((*child, kind, child_node.name.as_os_str()));
+            }
+
+            // the offset of an entry is the one to continue after it
+            let skip = usize::try_from(offset).unwrap_or(0);
+            for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(skip) {
+                if reply.add(ino, (i + 1) as i64, kind, name) {
+                    break;
+                }
+            }
+            reply.ok();
+        }
+
+        fn statfs(&mut self, _: &Request<'_>, _: u64, reply: fuser::ReplyStatfs) {
+            let files = self.tree().nodes.len() as u64;
+            reply.statfs(0, 0, 0, files, 0, 4096, 255, 0);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+// the servers catalog of conf.book_dir, every document
+// below it is known by its path relative to book_dir so
+// books with the same basename in different
+// subdirectories stay apart. The client mirrors the
+// same layout in its own book_dir.
+//
+// the listing sends the whole index, the client keeps
+// it to size its placeholders and to skip fetching
+// books it already has. The server keeps it in
+// conf.index_cache between sessions, every session is
+// its own process and hashing the whole library for
+// each of them adds up.
+
+use crate::{
+    shared_consts::*,
+    error::{QzbError, ProtocolError},
+    protocol::Message,
+    digest::{FileDigest, Hash},
+    sanitize::safe_rel_path,
+    conf::{Conf, Limits},
+};
+use std::{
+    fs,
+    process,
+    io::{self, Read},
+    ffi::OsStr,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    os::unix::fs::MetadataExt,
+};
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookEntry {
+    /// relative to conf.book_dir
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: i64,
+    pub hash: Hash,
+    pub doc_type: DocType,
+}
+
+/// the document formats zathura has plugins for,
+/// encoded as a single byte on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocType {
+    Pdf = 0,
+    Djvu = 1,
+    Epub = 2,
+    PostScript = 3,
+    Cbz = 4,
+    Other = 5,
+}
+
+impl DocType {
+    pub const SUPPORTED: &[Self] = &[
+        Self::Pdf, Self::Djvu, Self::Epub, Self::PostScript, Self::Cbz,
+    ];
+
+    pub fn from_u8(doc_type: u8) -> Result<Self, ProtocolError> {
+        return Ok(match doc_type {
+            0 => Self::Pdf,
+            1 => Self::Djvu,
+            2 => Self::Epub,
+            3 => Self::PostScript,
+            4 => Self::Cbz,
+            5 => Self::Other,
+            _ => Err(ProtocolError::MsgFormat)?,
+        });
+    }
+
+    /// by magic bytes, the extension only tells a comic
+    /// book archive apart from any other zip file.
+    pub fn detect(path: &Path) -> io::Result<Self> {
+        let mut head = Vec::with_capacity(64);
+        let _ = fs::File::open(path)?.take(64).read_to_end(&mut head)?;
+        let ext = path.extension()
+            .and_then(OsStr::to_str)
+            .map(str::to_ascii_lowercase);
+
+        return Ok(if head.starts_with(b"%PDF-") {
+            Self::Pdf
+        } else if head.starts_with(b"AT&TFORM") {
+            Self::Djvu
+        } else if head.starts_with(b"%!PS") {
+            Self::PostScript
+        } else if !head.starts_with(b"PK\x03\x04") {
+            Self::Other
+        } else if head.get(30..58) == Some(&b"mimetypeapplication/epub+zip"[..]) {
+            // epubs have to start with an uncompressed mimetype file
+            Self::Epub
+        } else if ext.as_deref() == Some("cbz") {
+            Self::Cbz
+        } else {
+            Self::Other
+        });
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BookIndex {
+    pub entries: Vec<BookEntry>,
+}
+
+impl BookIndex {
+    /// every regular file below book_dir, sorted by path.
+    /// Symlinks are skipped so the index can't loop or
+    /// leave book_dir. Books whose size and mtime match
+    /// their entry in prev aren't hashed again.
+    pub fn scan(book_dir: &Path, prev: &Self) -> DRes<Self> {
+        let prev = prev.entries.iter()
+            .map(|entry| (entry.path.as_path(), entry))
+            .collect();
+        let mut entries = vec!();
+        Self::scan_dir(book_dir, Path::new(""), &prev, &mut entries)?;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        return Ok(Self { entries });
+    }
+
+    /// scan of conf.book_dir with self as prev, a changed
+    /// index also replaces the one in conf.index_cache.
+    pub fn rescan(&mut self, conf: &Conf) -> DRes<()> {
+        let index = Self::scan(Path::new(&conf.book_dir), self)?;
+        if let Some(cache) = &conf.index_cache
+            && index != *self {
+            index.store(Path::new(cache))?;
+        }
+
+        *self = index;
+        return Ok(());
+    }
+
+    /// the index store last wrote to path, empty if there
+    /// is none or it can't be read.
+    pub fn load(path: &Path, limits: &Limits) -> Self {
+        let entries = fs::read(path).ok()
+            .and_then(|cached| Message::decode(&cached, limits).ok());
+        return match entries {
+            Some(Message::BookNames(entries)) => Self { entries },
+            _ => Self::default(),
+        };
+    }
+
+    /// writes the index to path in the encoding of a
+    /// BOOKNAMES message, replacing it at once so that
+    /// other sessions always load a whole one.
+    pub fn store(&self, path: &Path) -> DRes<()> {
+        let mut part = path.as_os_str().to_owned();
+        part.push(format!(".{}{PART_SUFFIX}", process::id()));
+        let encoded = Message::BookNames(self.entries.clone()).encode()?;
+        let written = fs::write(&part, encoded)
+            .and_then(|_| fs::rename(&part, path));
+        if written.is_err() {
+            let _ = fs::remove_file(&part);
+        }
+
+        written?;
+        return Ok(());
+    }
+
+    fn scan_dir(
+        dir: &Path,
+        rel: &Path,
+        prev: &HashMap<&Path, &BookEntry>,
+        entries: &mut Vec<BookEntry>,
+    ) -> DRes<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let ftype = entry.file_type()?;
+            let path = rel.join(entry.file_name());
+            if ftype.is_dir() {
+                Self::scan_dir(&entry.path(), &path, prev, entries)?;
+                continue;
+            } else if !ftype.is_file() {
+                continue;
+            }
+
+            let meta = entry.metadata()?;
+            let known = prev.get(path.as_path())
+                .filter(|old| old.size == meta.size() && old.mtime == meta.mtime());
+            if let Some(old) = known {
+                entries.push((*old).clone());
+                continue;
+            }
+
+            let fpath = entry.path();
+            let digest = FileDigest::of(&fpath)?;
+            entries.push(BookEntry {
+                path,
+                size: digest.size,
+                mtime: digest.mtime,
+                hash: digest.hash,
+                doc_type: DocType::detect(&fpath)?,
+            });
+        }
+
+        return Ok(());
+    }
+
+    /// looks a requested book up by its relative path, a
+    /// bare file name also matches a book in a subdirectory
+    /// as long as no other book has the same basename.
+    pub fn find(&self, name: &str) -> DRes<&BookEntry> {
+        let rel = safe_rel_path(Path::new(name))?;
+        if let Some(entry) = self.entries.iter().find(|entry| entry.path == rel) {
+            return Ok(entry);
+        }
+
+        if rel.components().count() != 1 {
+            return Err(QzbError::BookUnavailable);
+        }
+
+        let mut matches = self.entries.iter()
+            .filter(|entry| entry.path.file_name() == Some(OsStr::new(name)));
+        return match (matches.next(), matches.next()) {
+            (Some(entry), None) => Ok(entry),
+            (None, _) => Err(QzbError::BookUnavailable),
+            (Some(first), Some(second)) => Err(QzbError::AmbiguousBook {
+                name: name.to_owned(),
+                matches: [first, second].into_iter()
+                    .chain(matches)
+                    .map(|entry| entry.path.clone())
+                    .collect(),
+            }),
+        };
+    }
+}
@@ -1,389 +1,1115 @@
 use crate::{
     shared_consts::*, 
     shared_fn::*,
-    conf::Conf,
+    error::{QzbError, ProtocolError, ErrorCode},
+    protocol::Message,
+    watcher::{StateWatcher, StateEvent, WatchEvents},
+    digest::FileDigest,
+    book_index::BookIndex,
+    book_fs::{BookMount, FetchQueue, fetch_channel, errno},
+    open_watch::OpenWatcher,
+    notify::{NotifyConn, NotifyMsg},
+    event_loop::{EventLoop, ShutdownSignal},
+    db::{self, DbTable, is_db_file, synced_as_file},
+    sanitize::{resolve_in, check_path_limits, check_file_size, StateUsage},
+    conf::{Conf, StateBackend},
 };
 use std::{
-    num::TryFromIntError,
-    collections::HashMap,
-    time::Duration,
+    collections::{HashMap, HashSet},
+    mem,
+    process::Command,
+    time::{Duration, UNIX_EPOCH},
     fs::{self, ReadDir},
-    io::{self, Read, ErrorKind::*},
-    os::unix::net::{UnixStream, UnixListener},
+    io::{self, ErrorKind::*},
+    os::{fd::{AsRawFd, RawFd}, unix::net::UnixListener},
     path::{Path, PathBuf}, 
 };
 use qrexec_binds::{QrexecClient, QIO};
-use anyhow::anyhow;
+use dbuggery::append;
 
 
+/// pause before a new session after the last one
+/// ended with a recoverable error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// zathura instances talking to the notify socket at once
+const MAX_NOTIFY_CONNS: usize = 64;
+
+// what woke the session loop, see event_loop.rs
+const NOTIFY_READY: u64 = 0;
+const STATE_READY: u64 = 1;
+const FETCH_READY: u64 = 2;
+const PIPE_READY: u64 = 3;
+const SHUTDOWN_READY: u64 = 4;
+
 pub fn client_main(conf: Conf) -> DRes<()> {
+    // before the fuse and watcher threads are spawned
+    let shutdown = ShutdownSignal::new()?;
+    let mut book_tx = BookTx::new(CLIENT_ZATH_SOCK_PATH)?; 
+    let (fetcher, queue) = fetch_channel()?;
+    let opens = match (&conf.fuse_cache, conf.watch_opens) {
+        (Some(_), true) => Err(QzbError::Config(
+            "watch_opens has nothing to watch with fuse_cache set".to_owned()))?,
+        (Some(cache), false) => Opens::Mount(BookMount::mount(&conf, cache, fetcher)?),
+        (None, true) => Opens::Watch(OpenWatcher::spawn(&conf, fetcher)?),
+        (None, false) => Opens::None,
+    };
+
+    loop {
+        match session(&conf, &mut book_tx, &opens, &queue, &shutdown) {
+            // i.e. the server vm was restarted or a state
+            // file went away in the middle of a sync
+            Err(e) if e.is_recoverable() => {
+                log_err(&e);
+                if shutdown.sleep(RECONNECT_DELAY)?.is_some() {
+                    return Ok(());
+                }
+            }
+            res => return res,
+        }
+    }
+}
+
+/// runs one qrexec session until a shutdown signal
+/// arrives or something fails.
+fn session(
+    conf: &Conf,
+    book_tx: &mut BookTx,
+    opens: &Opens,
+    queue: &FetchQueue,
+    shutdown: &ShutdownSignal,
+) -> DRes<()> {
     const RPC_SERVICE_NAME: &str = "qubes.ZathuraMgmt";
 
-    let mut rbuf = [0u8; BLEN];
-    let mut qrx = QrexecClient::new::<KIB64>(
+    let qrx = QrexecClient::new::<KIB64>(
         &conf.target_vm, RPC_SERVICE_NAME,
-        None, None)?;
+        None, None)
+        .map_err(|e| QzbError::Qrexec(e.to_string()))?;
+    let pipe = qrx.read.as_raw_fd();
+    let mut rq = Requester::new(qrx);
 
-    initialize_files(&mut qrx, &conf, &mut rbuf)?;
+    rq.handshake(conf.profile.as_deref())?;
+    rq.initialize_files(conf)?;
+    match opens {
+        Opens::Mount(mount) => mount.set_index(rq.index())?,
+        Opens::Watch(watcher) => watcher.watch_tree()?,
+        Opens::None => (),
+    }
 
-    let mut book_tx = BookTx::new(CLIENT_ZATH_SOCK_PATH)?; 
-    let mut state_tx = StateFsTx::new();
+    let mut state_tx = StateFsTx::new(conf)?;
+    // sends the database, see StateFsTx::new
+    state_tx.sync(&mut rq, conf, Duration::ZERO)?;
+
+    let events = EventLoop::new()?;
+    events.add(book_tx.fd(), NOTIFY_READY)?;
+    events.add(state_tx.fd(), STATE_READY)?;
+    events.add(queue.fd(), FETCH_READY)?;
+    events.add(pipe, PIPE_READY)?;
+    events.add(shutdown.as_raw_fd(), SHUTDOWN_READY)?;
 
+    let mut ready = vec!();
     loop {
-        BookTx::handler(&mut book_tx, &mut rbuf, &mut qrx, &conf)?;
-        StateFsTx::handler(&mut state_tx, &mut rbuf, &mut qrx, &conf)?;
+        events.wait(&mut ready, None)?;
+        for token in &ready {
+            let res = match *token {
+                NOTIFY_READY => book_tx.handler(&mut rq, &mut state_tx, conf),
+                STATE_READY => state_tx.sync(&mut rq, conf, Duration::ZERO),
+                FETCH_READY => fetch_opened(queue, &mut rq, &mut state_tx, conf),
+                PIPE_READY => rq.unsolicited(conf),
+                SHUTDOWN_READY => {
+                    if shutdown.received()?.is_none() {
+                        continue;
+                    }
+                    // whatever zathura wrote last
+                    return state_tx.sync(&mut rq, conf, Duration::ZERO);
+                }
+                _ => Err(QzbError::Internal("unknown event loop token")),
+            };
+
+            match res {
+                // the server answered the request with ERROR,
+                // the session itself is fine.
+                Err(e @ QzbError::Remote { .. }) => report(&e),
+                res => res?,
+            }
+        }
     }
 }
 
-struct Requester {
-    qrx: QrexecClient,
+fn log_err(e: &QzbError) {
+    append(e.to_string(), ERR_FNAME, ERR_LOG_DIR_NAME);
+}
+
+/// logs e and shows it as a desktop notification,
+/// nothing else tells the user why a book never opened.
+fn report(e: &QzbError) {
+    log_err(e);
+    let _ = Command::new("notify-send")
+        .args(["zathura-bookmark", &e.to_string()])
+        .status();
+}
+
+/// what else besides the zathura socket triggers a book
+/// fetch, both go through the fetch channel.
+enum Opens {
+    Mount(BookMount),
+    Watch(OpenWatcher),
+    None,
+}
+
+/// fetches the books opened on the fuse mount or in the
+/// watched book_dir since the last call, see book_fs.rs. The opener is told how its
+/// fetch went, an ERROR from the server or a book it can't
+/// have only fails its open.
+pub fn fetch_opened<T: QIO>(
+    queue: &FetchQueue,
+    rq: &mut Requester<T>,
+    state_tx: &mut StateFsTx,
+    conf: &Conf,
+) -> DRes<()> {
+    for fetch in queue.pending() {
+        let res = match fetch.path.to_str() {
+            Some(bname) => rq.get_book(conf, bname)
+                .and_then(|_| state_tx.fetch(rq, conf)),
+            None => Err(ProtocolError::InvalidEnc.into()),
+        };
+
+        let _ = fetch.done.send(res.as_ref().map_err(errno).copied());
+        match res {
+            Err(e @ QzbError::Remote { .. }) => report(&e),
+            // the opener already knows
+            Err(e) if BookTx::is_callers(&e) => log_err(&e),
+            res => res?,
+        }
+    }
+
+    return Ok(());
+}
+
+/// the client end of a session, every request goes
+/// through one of the Request implementations below.
+pub struct Requester<T: QIO> {
+    qrx: T,
     buf: [u8; BLEN],
-    cursor: usize,
+
+    // the client side of Qmunnicate.data, whatever the
+    // next request is built from i.e. Book needs the
+    // book name in contents and again in handle.
+    data: Extra,
+
+    // the servers book index as of the last listing,
+    // without the books conf.book_formats leaves out.
+    index: BookIndex,
+    // where the last BOOK was written, relative to book_dir
+    resolved: Option<PathBuf>,
+    // set once a policy denial was reported, see skip_denied
+    denied: bool,
+    // what the state dir holds as of the last state file
+    // of the current exchange, see sanitize::check_state_write
+    usage: Option<StateUsage>,
 }
 
-// It doesn't make sense for there to be a single sender 
-// method on requester for all the Request structs.
+impl<T: QIO> Requester<T> {
+    pub fn new(qrx: T) -> Self {
+        Self {
+            qrx,
+            buf: [0u8; BLEN],
+            data: Extra::None,
+            index: BookIndex::default(),
+            resolved: None,
+            denied: false,
+            usage: None,
+        }
+    }
 
-impl Requester {
-    fn new(qrx: QrexecClient) -> Self {
-        Self { qrx, buf: [0u8; BLEN], cursor: 0 }
+    /// sends R and hands the servers response to R,
+    /// an ERROR response is returned as QzbError::Remote.
+    fn exchange<R: Request<T> + RecvOne<T>>(
+        &mut self,
+        conf: &Conf,
+        data: Extra,
+    ) -> DRes<()> {
+        self.data = data;
+        let res = R::send(self, conf).and_then(|_| R::recv(self, conf));
+        self.data = Extra::None;
+        self.usage = None;
+        return res;
+    }
+
+    /// sends our HELLO and checks the servers reply, the
+    /// profile picks a namespace on the server, see
+    /// protocol.rs and conf::Namespaces.
+    pub fn handshake(&mut self, profile: Option<&str>) -> DRes<()> {
+        send_msg(&mut self.qrx, &Message::hello_as(profile))?;
+        return recv_hello(&mut self.qrx, &mut self.buf)?.check_hello();
+    }
+
+    /// the server only ever answers requests, anything
+    /// on the pipe between them means it went away or
+    /// broke the protocol.
+    pub fn unsolicited(&mut self, conf: &Conf) -> DRes<()> {
+        return match try_recv_msg(&mut self.qrx, &mut self.buf, &conf.limits)? {
+            Some(_) => Err(ProtocolError::UnexpectedMsg)?,
+            None => Err(QzbError::PeerClosed),
+        };
+    }
+
+    /// the servers book index as of the last listing.
+    pub fn index(&self) -> &BookIndex {
+        return &self.index;
+    }
+
+    /// a policy denying the listing or the state only
+    /// skips that step.
+    pub fn initialize_files(&mut self, conf: &Conf) -> DRes<()> {
+        let listed = self.exchange::<BookNames>(conf, Extra::None);
+        self.skip_denied(listed)?;
+        let fetched = self.get_state(conf);
+        return self.skip_denied(fetched);
+    }
+
+    /// turns a PERMISSION_DENIED answer into Ok, the step
+    /// of the sync it answered is left out. Only the first
+    /// one is reported, a read only policy denies every
+    /// upload zathura causes.
+    pub fn skip_denied(&mut self, res: DRes<()>) -> DRes<()> {
+        return match res {
+            Err(e @ QzbError::Remote { code: ErrorCode::PermissionDenied, .. }) => {
+                if !mem::replace(&mut self.denied, true) {
+                    report(&e);
+                }
+                Ok(())
+            }
+            res => res,
+        };
+    }
+
+    /// fetches the state files and with the sqlite
+    /// backend the database tables as well.
+    pub fn get_state(&mut self, conf: &Conf) -> DRes<()> {
+        self.exchange::<StateFiles>(conf, Extra::None)?;
+        if conf.state_backend == StateBackend::Sqlite {
+            self.exchange::<DbTables>(conf, Extra::None)?;
+        }
+
+        return Ok(());
+    }
+
+    /// fetches bname unless the listing says the local
+    /// copy is already the same as the servers, returns
+    /// the path below book_dir bname resolved to.
+    pub fn get_book(&mut self, conf: &Conf, bname: &str) -> DRes<PathBuf> {
+        if let Ok(entry) = self.index.find(bname) {
+            let local = Path::new(conf.local_book_dir()).join(&entry.path);
+            if FileDigest::of(&local).is_ok_and(|digest| digest.hash == entry.hash) {
+                return Ok(entry.path.clone());
+            }
+        }
+
+        self.exchange::<Book>(conf, Extra::FileName(bname.to_owned()))?;
+        return match self.resolved.take() {
+            Some(rel) => Ok(rel),
+            None => Err(QzbError::Internal("BOOK handled without its path")),
+        };
+    }
+
+    /// uploads files and directories below conf.state_dir,
+    /// more than one go out as a single batch.
+    pub fn send_files(&mut self, conf: &Conf, fpaths: Vec<PathBuf>) -> DRes<()> {
+        // nothing would be sent and nothing answered
+        if fpaths.is_empty() {
+            return Ok(());
+        }
+
+        return self.exchange::<StateUpload>(conf, Extra::Paths(fpaths));
+    }
+
+    pub fn send_remove(&mut self, conf: &Conf, fpath: &Path) -> DRes<()> {
+        return self.exchange::<StateRemove>(conf, Extra::Paths(vec!(fpath.to_owned())));
+    }
+
+    pub fn send_rename(&mut self, conf: &Conf, from: &Path, to: &Path) -> DRes<()> {
+        return self.exchange::<StateRename>(
+            conf, Extra::Paths(vec!(from.to_owned(), to.to_owned())));
+    }
+
+    pub fn send_db(&mut self, conf: &Conf, tables: Vec<DbTable>) -> DRes<()> {
+        return self.exchange::<DbUpload>(conf, Extra::Tables(tables));
     }
 }
- 
-// on this side the request is one of:
-// - Content::One(x)
-// - Content::More(x)
-// - Content::None = Ident condition
 
-trait Request<const id: u8> {
-    fn send(qrx: &mut Requester, conf: &Conf) -> DRes<()> {
-        let cont = Self::contents(conf, qrx)?;
-        return match cont {
-            Content::One(cont) => Self::send_one(qrx, cont)?,
-            Content::More(cont) => Self::send_more(qrx, cont)?,
+// the client side of the servers Send / RecvOne / RecvMore,
+// Request builds what the server receives and RecvOne
+// handles what the servers Send implementation sent back.
+
+trait Request<T: QIO> {
+    fn send(rq: &mut Requester<T>, conf: &Conf) -> DRes<()> {
+        let cont = Self::contents(conf, rq)?;
+        match cont {
+            Content::One(msg) => send_msg(&mut rq.qrx, &msg)?,
+            Content::More(msgs) => Self::send_more(rq, msgs)?,
             Content::None => (),
+        }
+
+        return Ok(());
+    }
+
+    fn send_more(rq: &mut Requester<T>, msgs: Vec<Message>) -> DRes<()> {
+        for msg in msgs {
+            send_msg(&mut rq.qrx, &msg)?;
+        }
+
+        return Ok(());
+    }
+
+    fn contents(conf: &Conf, rq: &mut Requester<T>) -> DRes<Content>;
+}
+
+trait RecvOne<T: QIO> {
+    fn recv(rq: &mut Requester<T>, conf: &Conf) -> DRes<()> {
+        let msg = recv_msg(&mut rq.qrx, &mut rq.buf, &conf.limits)?;
+        Self::handle(rq, conf, msg)?;
+        return Ok(());
+    }
+
+    fn handle(rq: &mut Requester<T>, conf: &Conf, msg: Message) -> DRes<()>;
+}
+
+trait RecvMore<T: QIO>: RecvOne<T> {
+    /// receives the num messages announced by the
+    /// response, each one goes through RecvOne::handle.
+    /// Like on the server the whole batch is read even if
+    /// handling one of them fails, the first error is
+    /// returned after.
+    fn recv_more(rq: &mut Requester<T>, conf: &Conf, num: u32) -> DRes<()> {
+        let mut res = Ok(());
+        for _ in 0..num {
+            let msg = recv_msg(&mut rq.qrx, &mut rq.buf, &conf.limits)?;
+            let handled = Self::handle(rq, conf, msg);
+            if res.is_ok() {
+                res = handled;
+            }
+        }
+
+        return res;
+    }
+}
+
+struct BookNames;
+impl<T: QIO> Request<T> for BookNames {
+    fn contents(_: &Conf, _: &mut Requester<T>) -> DRes<Content> {
+        return Ok(Content::One(Message::GetBookNames));
+    }
+}
+
+impl<T: QIO> RecvOne<T> for BookNames {
+    /// books only show up as sparse placeholders with
+    /// the size and mtime of the real book until zathura
+    /// asks for them, at the same path below book_dir as
+    /// on the server. A single hostile entry fails the
+    /// whole listing, a book over max_file_size is only
+    /// left out. The fuse mount shows the listing itself
+    /// so there are no placeholders then.
+    fn handle(rq: &mut Requester<T>, conf: &Conf, msg: Message) -> DRes<()> {
+        let mut books = match msg {
+            Message::BookNames(books) => books,
+            Message::NoContent => vec!(),
+            _ => Err(ProtocolError::UnexpectedMsg)?,
+        };
+
+        books.retain(|book| {
+            if !conf.book_formats.contains(&book.doc_type) {
+                return false;
+            }
+
+            let fits = usize::try_from(book.size).map_err(QzbError::from)
+                .and_then(|size| check_file_size(&conf.limits, size));
+            if let Err(e) = &fits {
+                append(format!("left out {:?}: {e}", book.path), ERR_FNAME, ERR_LOG_DIR_NAME);
+            }
+            return fits.is_ok();
+        });
+
+        for book in &books {
+            check_path_limits(&conf.limits, &book.path)?;
+            let path = resolve_in(Path::new(&conf.book_dir), &book.path)?;
+            if conf.fuse_cache.is_some() || fs::exists(&path)? { continue; }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let placeholder = fs::File::create(&path)?;
+            placeholder.set_len(book.size)?;
+            if let Ok(secs) = u64::try_from(book.mtime) {
+                placeholder.set_modified(UNIX_EPOCH + Duration::from_secs(secs))?;
+            }
+        }
+
+        rq.index = BookIndex { entries: books };
+        return Ok(());
+    }
+}
+
+struct Book;
+impl Book {
+    fn bname<T: QIO>(rq: &Requester<T>) -> DRes<&str> {
+        return match &rq.data {
+            Extra::FileName(bname) => Ok(bname),
+            _ => Err(QzbError::Internal("Book without a book name"))?,
         };
     }
+}
 
-    //fn send_one(qrx: &mut Requester, cont: Vec<u8>) -> DRes<()> {
-    //    Self::set_numreads(qrx, cont.len());
-    //    let mut max_cursor;
-    //    while num_reads != 0 {
-    //        if num_reads = 1 
-    //    } 
+impl<T: QIO> Request<T> for Book {
+    fn contents(_: &Conf, rq: &mut Requester<T>) -> DRes<Content> {
+        return Ok(Content::One(Message::GetBook(Self::bname(rq)?.to_owned())));
+    }
+}
 
-    //    return Ok(());
-    //}
+impl<T: QIO> RecvOne<T> for Book {
+    fn handle(rq: &mut Requester<T>, conf: &Conf, msg: Message) -> DRes<()> {
+        let Message::Book { path: rel, contents } = msg else {
+            return Err(ProtocolError::UnexpectedMsg.into());
+        };
+        check_file_size(&conf.limits, contents.len())?;
 
-    fn send_more(qrx: &mut Requester, conts: Vec<Vec<u8>>) -> DRes<()> {
+        // a bare name can come back as the path of a book in
+        // a subdirectory, anything else isn't what we asked for.
+        let (rel, asked) = (Path::new(&rel), Path::new(Self::bname(rq)?));
+        let bare = asked.components().count() == 1;
+        if rel != asked && !(bare && rel.file_name() == asked.file_name()) {
+            Err(ProtocolError::UnexpectedMsg)?;
+        }
 
+        let path = resolve_in(Path::new(conf.local_book_dir()), rel)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+        rq.resolved = Some(rel.to_owned());
         return Ok(());
     }
+}
+
+struct StateFiles;
+impl<T: QIO> Request<T> for StateFiles {
+    /// the manifest lists every state file we already
+    /// have so the server only sends what differs.
+    fn contents(conf: &Conf, _: &mut Requester<T>) -> DRes<Content> {
+        let mut digests = HashMap::new();
+        let _ = StateFsTx::state_fs_changes(
+            &mut digests, fs::read_dir(&conf.state_dir)?)?;
+
+        let mut manifest = vec!();
+        for (path, digest) in digests {
+            let path = rel_state_path(conf, &path)?;
+            if synced_as_file(conf, &path) {
+                manifest.push((path, digest.hash));
+            }
+        }
+
+        return Ok(Content::One(Message::GetStateFiles(manifest)));
+    }
+}
+
+impl<T: QIO> RecvMore<T> for StateFiles {}
+
+impl<T: QIO> RecvOne<T> for StateFiles {
+    /// NUM_SFILES only ever opens the response, the batch
+    /// it announces is nothing but SFILEs.
+    fn recv(rq: &mut Requester<T>, conf: &Conf) -> DRes<()> {
+        return match recv_msg(&mut rq.qrx, &mut rq.buf, &conf.limits)? {
+            Message::NumStateFiles(num) => Self::recv_more(rq, conf, num),
+            Message::NoContent => Ok(()),
+            _ => Err(ProtocolError::UnexpectedMsg)?,
+        };
+    }
+
+    fn handle(rq: &mut Requester<T>, conf: &Conf, msg: Message) -> DRes<()> {
+        return match msg {
+            msg @ Message::StateFile { .. } => write_state_file(conf, &mut rq.usage, msg),
+            _ => Err(ProtocolError::UnexpectedMsg)?,
+        };
+    }
+}
+
+struct StateUpload;
+impl<T: QIO> Request<T> for StateUpload {
+    /// a batch is announced with NUM_SFILES like the
+    /// servers response to GET_SFILES.
+    fn contents(conf: &Conf, rq: &mut Requester<T>) -> DRes<Content> {
+        let mut msgs = vec!();
+        for fpath in request_paths(rq)? {
+            let is_dir = fpath.is_dir();
+            let contents = if is_dir {
+                vec!()
+            } else {
+                fs::read(fpath)?
+            };
+
+            msgs.push(Message::StateFile {
+                path: rel_state_path(conf, fpath)?,
+                is_dir,
+                contents,
+            });
+        }
+
+        return Ok(match msgs.len() {
+            0 => Content::None,
+            1 => Content::One(msgs.remove(0)),
+            num => {
+                msgs.insert(0, Message::NumStateFiles(num.try_into()?));
+                Content::More(msgs)
+            }
+        });
+    }
+}
+
+impl<T: QIO> RecvOne<T> for StateUpload {
+    fn handle(_: &mut Requester<T>, _: &Conf, msg: Message) -> DRes<()> {
+        return expect_no_content(msg);
+    }
+}
+
+struct StateRemove;
+impl<T: QIO> Request<T> for StateRemove {
+    fn contents(conf: &Conf, rq: &mut Requester<T>) -> DRes<Content> {
+        let [fpath] = request_paths(rq)? else {
+            return Err(QzbError::Internal("request without the data it is built from"));
+        };
+
+        return Ok(Content::One(Message::RemoveState(rel_state_path(conf, fpath)?)));
+    }
+}
+
+impl<T: QIO> RecvOne<T> for StateRemove {
+    fn handle(_: &mut Requester<T>, _: &Conf, msg: Message) -> DRes<()> {
+        return expect_no_content(msg);
+    }
+}
+
+struct StateRename;
+impl<T: QIO> Request<T> for StateRename {
+    fn contents(conf: &Conf, rq: &mut Requester<T>) -> DRes<Content> {
+        let [from, to] = request_paths(rq)? else {
+            return Err(QzbError::Internal("request without the data it is built from"));
+        };
+
+        return Ok(Content::One(Message::RenameState {
+            from: rel_state_path(conf, from)?,
+            to: rel_state_path(conf, to)?,
+        }));
+    }
+}
 
-    fn set_numreads(
-        qrx: &mut Requester,
-        msg_len: usize,
-    ) -> Result<u32, TryFromIntError> {
-        let (nrb, nr) = num_reads_encode(msg_len)?;
-        qrx.cursor += set_slice(&mut qrx.buf, &nrb);
-        return Ok(nr);
+impl<T: QIO> RecvOne<T> for StateRename {
+    fn handle(_: &mut Requester<T>, _: &Conf, msg: Message) -> DRes<()> {
+        return expect_no_content(msg);
     }
+}
 
-    fn contents(conf: &Conf, qrx: &mut Requester) -> DRes<Content>; 
+struct DbTables;
+impl<T: QIO> Request<T> for DbTables {
+    fn contents(_: &Conf, _: &mut Requester<T>) -> DRes<Content> {
+        return Ok(Content::One(Message::GetDbTables));
+    }
 }
 
+impl<T: QIO> RecvOne<T> for DbTables {
+    /// the empty base keeps rows the server doesn't know about.
+    fn handle(_: &mut Requester<T>, conf: &Conf, msg: Message) -> DRes<()> {
+        let Message::DbTables(tables) = msg else {
+            return Err(ProtocolError::UnexpectedMsg.into());
+        };
 
-struct BookTx { 
+        let mut conn = db::open(&db::db_path(conf))?;
+        return db::apply_tables(&mut conn, &[], &tables, conf.limits.max_state_size);
+    }
+}
+
+struct DbUpload;
+impl<T: QIO> Request<T> for DbUpload {
+    fn contents(_: &Conf, rq: &mut Requester<T>) -> DRes<Content> {
+        return match mem::replace(&mut rq.data, Extra::None) {
+            Extra::Tables(tables) => Ok(Content::One(Message::DbTables(tables))),
+            _ => Err(QzbError::Internal("request without the data it is built from"))?,
+        };
+    }
+}
+
+impl<T: QIO> RecvOne<T> for DbUpload {
+    fn handle(_: &mut Requester<T>, _: &Conf, msg: Message) -> DRes<()> {
+        return expect_no_content(msg);
+    }
+}
+
+/// the servers answer to every upload that went through.
+fn expect_no_content(msg: Message) -> DRes<()> {
+    return match msg {
+        Message::NoContent => Ok(()),
+        _ => Err(ProtocolError::UnexpectedMsg)?,
+    };
+}
+
+fn request_paths<T: QIO>(rq: &Requester<T>) -> DRes<&[PathBuf]> {
+    return match &rq.data {
+        Extra::Paths(fpaths) => Ok(fpaths),
+        _ => Err(QzbError::Internal("request without the data it is built from"))?,
+    };
+}
+
+/// the notify socket zathura and qzb-open talk to, see
+/// notify.rs for the protocol.
+pub struct BookTx {
     sock: UnixListener,
-    conn: Option<UnixStream>, 
+    sock_path: PathBuf,
+    conns: Vec<NotifyConn>,
+    // the listener and every caller, readable whenever
+    // the handler has something to do.
+    events: EventLoop,
 }
+
 impl BookTx {
     // binds the zathura unix stream socket
-    fn new(sock_path: impl AsRef<Path>) -> io::Result<Self> {
+    pub fn new(sock_path: impl AsRef<Path>) -> io::Result<Self> {
         let sock = UnixListener::bind(sock_path.as_ref())?;
-        let conn = None;
-        return Ok(Self { sock, conn }); 
+        sock.set_nonblocking(true)?;
+        let events = EventLoop::new()?;
+        events.add(sock.as_raw_fd(), 0)?;
+        return Ok(Self {
+            sock,
+            sock_path: sock_path.as_ref().to_owned(),
+            conns: vec!(),
+            events,
+        });
     }
 
-    /// blocks until zathura connects to the socket,
-    /// returns immediately if conn is already Some(stream).
-    /// don't call this directly, handler will call this.
-    fn connect(&mut self) -> io::Result<()> {
-        if self.conn.is_some() {
-            return Ok(());
-        }
+    /// readable when a caller connected or sent something.
+    pub fn fd(&self) -> RawFd {
+        return self.events.as_raw_fd();
+    }
 
-        let (stream, _) = self.sock.accept()?;
-        stream.set_nonblocking(true)?;
-        stream.set_read_timeout(Some(Duration::from_secs(3)))?;
-        self.conn = Some(stream);
-        return Ok(()); 
-    } 
+    /// accepts every waiting caller, past MAX_NOTIFY_CONNS
+    /// new callers are hung up on right away.
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            let (stream, _) = match self.sock.accept() {
+                Ok(conn) => conn,
+                Err(e) if e.kind() == WouldBlock => return Ok(()),
+                Err(e) if e.kind() == Interrupted => continue,
+                Err(e) => return Err(e),
+            };
 
-    fn handler(
+            if self.conns.len() < MAX_NOTIFY_CONNS {
+                self.events.add(stream.as_raw_fd(), 0)?;
+                self.conns.push(NotifyConn::new(stream)?);
+            }
+        }
+    }
+
+    /// answers every command that arrived since the last
+    /// call, callers that hung up or broke the protocol
+    /// are dropped. Only errors the session can't go on
+    /// after are returned, the caller hears about them too.
+    pub fn handler<T: QIO>(
         &mut self,
-        rbuf: &mut [u8; BLEN],
-        qrx: &mut QrexecClient,
+        rq: &mut Requester<T>,
+        state_tx: &mut StateFsTx,
         conf: &Conf,
     ) -> DRes<()> {
-        if self.conn.is_none() {
-            self.connect()?;
-        }
+        self.accept()?;
 
-        let mut conn = self.conn.take().unwrap();
-        let res = conn.read(rbuf);
-        let nb = match res {
-            Ok(0) => {
-                self.conn = None;
-                return Ok(());
-            }
-            Ok(nb) => nb,
-            Err(e) if e.kind() == WouldBlock || e.kind() == Interrupted => {
-                return Ok(());    
+        let mut res = Ok(());
+        let mut conns = mem::take(&mut self.conns);
+        conns.retain_mut(|conn| {
+            let msgs = match conn.poll() {
+                Ok(Some(msgs)) => msgs,
+                Ok(None) => return false,
+                Err(e) => {
+                    let _ = conn.send(&NotifyMsg::failed(&e));
+                    return false;
+                }
+            };
+
+            for msg in msgs {
+                if !conn.is_greeted() {
+                    match conn.greet(&msg) {
+                        Ok(true) => continue,
+                        Ok(false) => return false,
+                        Err(e) => {
+                            let _ = conn.send(&NotifyMsg::failed(&e));
+                            return false;
+                        }
+                    }
+                }
+
+                let reply = match Self::command(msg, rq, state_tx, conf) {
+                    Ok(reply) => reply,
+                    // only this callers request failed
+                    Err(e) if matches!(e, QzbError::Remote { .. }) || Self::is_callers(&e) => {
+                        if matches!(e, QzbError::Remote { .. }) {
+                            report(&e);
+                        }
+                        NotifyMsg::failed(&e)
+                    }
+                    Err(e) => {
+                        let _ = conn.send(&NotifyMsg::failed(&e));
+                        res = Err(e);
+                        return false;
+                    }
+                };
+
+                if conn.send(&reply).is_err() {
+                    return false;
+                }
             }
-            Err(e) => Err(e)?,
-        };
 
-        let msg_len = u32::from_ne_bytes(rbuf[..4].try_into()?);
-        if rbuf[4..6] != *ZBOOK_READ_NOTIFY || msg_len != nb.try_into()? {
-            Err(anyhow!(MSG_FORMAT_ERR))?; 
+            return true;
+        });
+        self.conns = conns;
+
+        return res;
+    }
+
+    fn command<T: QIO>(
+        msg: NotifyMsg,
+        rq: &mut Requester<T>,
+        state_tx: &mut StateFsTx,
+        conf: &Conf,
+    ) -> DRes<NotifyMsg> {
+        match msg {
+            NotifyMsg::Opened(book) => {
+                let rel = rq.get_book(conf, &Self::book_name(conf, &book))?;
+
+                // a server slicing the state by book only
+                // has this books state to send from now on.
+                state_tx.fetch(rq, conf)?;
+                let path = Path::new(&conf.book_dir).join(rel);
+                return Ok(NotifyMsg::Ready(path.to_str()
+                    .ok_or(ProtocolError::InvalidEnc)?
+                    .to_owned()));
+            }
+            NotifyMsg::Closed(_) | NotifyMsg::BookmarkAdded { .. } => {
+                state_tx.sync(rq, conf, Duration::ZERO)?;
+            }
+            NotifyMsg::SyncNow => state_tx.fetch(rq, conf)?,
+            NotifyMsg::PageChanged { .. } => (),
+            NotifyMsg::Hello(_) | NotifyMsg::Ready(_) | NotifyMsg::Ok | NotifyMsg::Failed { .. } => {
+                return Ok(NotifyMsg::failed(&ProtocolError::UnexpectedMsg.into()));
+            }
         }
 
-        let bname = str::from_utf8(&rbuf[6..(msg_len as usize)])? .to_owned();
+        return Ok(NotifyMsg::Ok);
+    }
 
-        get_book(qrx, conf, &bname, rbuf)?;
+    /// zathura may name the book by the path it opened
+    fn book_name(conf: &Conf, book: &str) -> String {
+        return match Path::new(book).strip_prefix(&conf.book_dir) {
+            Ok(rel) => rel.to_str().unwrap_or(book).to_owned(),
+            Err(_) => book.to_owned(),
+        };
+    }
 
-        self.conn = Some(conn);
-        return Ok(());
+    /// errors caused by the book the caller named
+    fn is_callers(e: &QzbError) -> bool {
+        return matches!(e,
+            QzbError::BookUnavailable
+            | QzbError::AmbiguousBook { .. }
+            | QzbError::PathSafety { .. }
+            | QzbError::LimitExceeded { .. });
+    }
+}
+
+impl Drop for BookTx {
+    /// a leftover socket file keeps the next client
+    /// from binding.
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.sock_path);
     }
 }
 
 pub struct StateFsTx {
-    fs_states: HashMap<PathBuf, String>,
+    fs_states: HashMap<PathBuf, FileDigest>,
+    watcher: StateWatcher,
+    // the tables last sent to the server, None unless
+    // the sqlite backend is in use.
+    last_db: Option<Vec<DbTable>>,
+    db_dirty: bool,
 }
 
 impl StateFsTx {
-    fn new() -> Self {
-        let fs_states = HashMap::new();
-        return Self { fs_states };
+    /// call after the state dir has been fetched from the
+    /// server so the fetched files aren't sent straight back.
+    pub fn new(conf: &Conf) -> DRes<Self> {
+        let mut watcher = StateWatcher::new(&conf.state_dir)?;
+        let mut fs_states = HashMap::new();
+        let _ = Self::state_fs_changes(
+            &mut fs_states, fs::read_dir(&conf.state_dir)?)?;
+
+        // zathura keeps the database open, its writes never
+        // close the file. Starting out dirty sends whatever
+        // the local database had before the server tables
+        // were applied to it.
+        let (last_db, db_dirty) = match conf.state_backend {
+            StateBackend::Sqlite => {
+                watcher.watch_modify(Path::new(&conf.state_dir))?;
+                (Some(vec!()), true)
+            }
+            StateBackend::Plain => (None, false),
+        };
+
+        return Ok(Self { fs_states, watcher, last_db, db_dirty });
+    }
+
+    /// readable when the state dir changed.
+    pub fn fd(&self) -> RawFd {
+        return self.watcher.fd();
     }
 
-    fn handler(
+    /// blocks for at most timeout waiting on the watcher,
+    /// then sends whatever actually changed. A denied
+    /// upload isn't an error, see Requester::skip_denied.
+    pub fn sync<T: QIO>(
         &mut self,
-        rbuf: &mut [u8; BLEN],
-        qrx: &mut QrexecClient,
+        rq: &mut Requester<T>,
         conf: &Conf,
+        timeout: Duration,
     ) -> DRes<()> {
-        let fchanged = Self::state_fs_changes(
-            &mut self.fs_states, fs::read_dir(&conf.state_dir)?)?; 
-    
-        for file in fchanged {
-            send_file(qrx, &file, rbuf, file.is_dir())?;
-        }
-    
-        return Ok(());
+        let res = self.send_changes(rq, conf, timeout);
+        return rq.skip_denied(res);
     }
-    
-    // only public so I don't have to make another test module
-    // inside this one.
-    /// returns a vector of PathBuf's which have been changed
-    /// inside of the conf.state_dir fields indicated directory
-    /// which is monitored recursively. 
-    pub fn state_fs_changes(
-        fs_states: &mut HashMap<PathBuf, String>,
-        read_dir: ReadDir, 
-    ) -> DRes<Vec<PathBuf>> {
-        let mut fupdates = vec!();
-        let mut current_files = vec!();
-        for entry in read_dir {
-            let file = entry?;
-            let fpath = file.path();
-    
-            if fpath.is_dir() {
-                let changes = Self::state_fs_changes(fs_states, fs::read_dir(&fpath)?)?;
-                fupdates.extend_from_slice(&changes[..changes.len()]);
+
+    fn send_changes<T: QIO>(
+        &mut self,
+        rq: &mut Requester<T>,
+        conf: &Conf,
+        timeout: Duration,
+    ) -> DRes<()> {
+        let events = match self.watcher.wait(timeout)? {
+            WatchEvents::Changed(events) => events,
+            WatchEvents::Overflow => self.rescan(conf)?,
+        };
+
+        // consecutive writes go out as one batch, anything
+        // else sends the batch first to keep the order.
+        let mut written = vec!();
+        for event in events {
+            if self.last_db.is_some() && touches_db(&event) {
+                self.db_dirty = true;
+                continue;
             }
 
-            current_files.push(fpath.clone());
-    
-            let file_string = fs::read_to_string(&fpath)?;
-            let mref_kval = fs_states.get_mut(&fpath);
-            if let Some(mref_kval) = mref_kval {
-                if *mref_kval != file_string {
-                    fupdates.push(fpath); 
+            match event {
+                StateEvent::Written(path) => {
+                    // zathura writes through temp files which
+                    // are often renamed away by now.
+                    if path.exists()
+                        && Self::record_state(&mut self.fs_states, &path)? {
+                        written.push(path);
+                    }
+                }
+                StateEvent::Removed(path) => {
+                    rq.send_files(conf, mem::take(&mut written))?;
+                    if self.forget(&path) {
+                        rq.send_remove(conf, &path)?;
+                    }
+                }
+                StateEvent::Renamed { from, to } => {
+                    rq.send_files(conf, mem::take(&mut written))?;
+                    if self.rekey(&from, &to) {
+                        rq.send_rename(conf, &from, &to)?;
+                    }
+
+                    // also covers a rename from something
+                    // the server never saw.
+                    if to.exists()
+                        && Self::record_state(&mut self.fs_states, &to)? {
+                        written.push(to);
+                    }
                 }
-            } else {
-                let _ = fs_states.insert(fpath.clone(), file_string);
-                fupdates.push(fpath);
             }
         }
-    
-        // going to avoid filter here because it could be really expensive
-        let mut del_list = vec!();
-        for (key, _) in fs_states.iter() {
-            if !current_files.contains(key) {
-                del_list.push(key.clone());
-            } 
-        }
-    
-        for key in del_list {
-            let _ = fs_states.remove(&key);
+        rq.send_files(conf, written)?;
+
+        if self.db_dirty {
+            self.db_dirty = false;
+            self.sync_db(rq, conf)?;
         }
     
-        return Ok(fupdates);
+        return Ok(());
     }
-}
 
-fn initialize_files(
-    qrx: &mut QrexecClient,
-    conf: &Conf, 
-    rbuf: &mut [u8; BLEN],
-) -> DRes<()> {
-    get_booknames(qrx, conf, rbuf)?;
-    get_state_fs(qrx, conf, rbuf)?;
+    /// get_state without losing local changes, whatever
+    /// zathura wrote is sent first. What the server sent
+    /// back is recorded so it isn't uploaded again once
+    /// the watcher reports the writes.
+    pub fn fetch<T: QIO>(
+        &mut self,
+        rq: &mut Requester<T>,
+        conf: &Conf,
+    ) -> DRes<()> {
+        self.sync(rq, conf, Duration::ZERO)?;
+        let fetched = rq.get_state(conf);
+        rq.skip_denied(fetched)?;
 
-    return Ok(());
-}
+        // unlike state_fs_changes nothing is dropped, a
+        // removal still has to go out with its event.
+        let _ = Self::walk_changes(
+            &mut self.fs_states, fs::read_dir(&conf.state_dir)?, &mut HashSet::new())?;
+        if self.last_db.is_some() {
+            self.last_db = Some(db::read_tables(&db::open(&db::db_path(conf))?)?);
+        }
 
-fn get_booknames(
-    qrx: &mut QrexecClient,
-    conf: &Conf, 
-    rbuf: &mut [u8; BLEN],
-) -> DRes<()> {
-    let mut bnames = vec!();
-    let mut rnb;
-    let mut cont;
-    let num_reads;
-
-    macro_rules! push_names {
-        ($vec_names:expr, $buf:expr) => {
-            $vec_names.extend(
-                str::from_utf8($buf)?
-                    .split(';')
-                    .filter_map({ |x| 
-                        if !x.is_empty() { 
-                            Some(x.to_string())
-                        } else {
-                            None
-                        } 
-                    }
-                )
-            ) 
-        }; 
+        return Ok(());
     }
 
-    qrx.write(GET_BOOKNAMES)?;
-    rnb = qrx.read(rbuf)?;
-    qrx.write(RECV_SEQ)?;
+    /// sends the synced tables if they changed since
+    /// they were last sent.
+    fn sync_db<T: QIO>(
+        &mut self,
+        rq: &mut Requester<T>,
+        conf: &Conf,
+    ) -> DRes<()> {
+        let tables = db::read_tables(&db::open(&db::db_path(conf))?)?;
+        if self.last_db.as_ref() == Some(&tables) {
+            return Ok(());
+        }
 
-    if rbuf.starts_with(NONE) {
+        rq.send_db(conf, tables.clone())?;
+        self.last_db = Some(tables);
         return Ok(());
-    } 
-
-    cont = &rbuf[..rnb];
-    let delim_idx = find_delim(cont, b';').ok_or(
-        anyhow!(MSG_FORMAT_ERR))?;
-
-    let split = cont.split_at(delim_idx);
-    let num_reads_bytes = split.0;
-    cont = &split.1[1..];
-
-    num_reads = num_reads_decode(num_reads_bytes.try_into()?);
-
-    push_names!(bnames, cont);
-
-    for _ in 0..(num_reads - 1) {
-        rnb = qrx.read(rbuf)?;
-        qrx.write(RECV_SEQ)?;
-        push_names!(bnames, &rbuf[..rnb]);
-    } 
-
-    for bname in bnames {
-        let path = format!("{}/{}", conf.book_dir, bname);
-        if fs::exists(&path)? { continue; }
-        fs::File::create(&path)?;
     }
 
-    return Ok(());
-}
-
-fn get_book(
-    qrx: &mut QrexecClient,
-    conf: &Conf,
-    bname: &str, 
-    rbuf: &mut [u8; BLEN], 
-) -> DRes<()> {
-    let mut book = Vec::<u8>::new();
-    let mut rnb: usize;
-
-    let mut query = vec!();
-        query.extend_from_slice(VAR_GET_BOOK);
-        query.extend_from_slice(bname.as_bytes());
-        query.push(b';');
-    let qlen = query.len();
-    assert!(qlen < BLEN, "{}", MSG_LEN_WBUF_ERR);
+    /// drops path and everything below it from fs_states,
+    /// returns false if path was never recorded.
+    fn forget(&mut self, path: &Path) -> bool {
+        let known = self.fs_states.contains_key(path);
+        self.fs_states.retain(|key, _| !key.starts_with(path));
+        return known;
+    }
 
-    qrx.write(&query)?; 
-    rnb = qrx.read(rbuf)?;
-    qrx.write(RECV_SEQ)?;
+    /// moves the recorded state of from and everything below
+    /// it over to to, returns false if from was never recorded.
+    fn rekey(&mut self, from: &Path, to: &Path) -> bool {
+        if !self.fs_states.contains_key(from) {
+            return false;
+        }
 
-    let delim_idx = find_delim(&rbuf[..rnb], b';')
-        .ok_or(anyhow!(MSG_FORMAT_ERR))?;
+        let moved: Vec<PathBuf> = self.fs_states.keys()
+            .filter(|key| key.starts_with(from))
+            .cloned()
+            .collect();
 
-    let (header, cont) = (
-        &rbuf[..delim_idx],
-        &rbuf[(delim_idx+1)..rnb]); 
+        for key in moved {
+            if let (Some(state), Ok(rel)) = (self.fs_states.remove(&key), key.strip_prefix(from)) {
+                let _ = self.fs_states.insert(to.join(rel), state);
+            }
+        }
 
-    let num_reads_bytes = header
-        .split(|x|  *x == b':')
-        .skip(2)
-        .next()
-        .ok_or(anyhow!(MSG_FORMAT_ERR))?;
+        return true;
+    }
 
-    let num_reads = num_reads_decode(
-        num_reads_bytes.try_into()?);
+    /// full walk of the state dir for when the watcher
+    /// lost events, removals are found by comparing
+    /// fs_states before and after.
+    fn rescan(&mut self, conf: &Conf) -> DRes<Vec<StateEvent>> {
+        let before: Vec<PathBuf> = self.fs_states.keys().cloned().collect();
+        let mut events: Vec<StateEvent> = Self::state_fs_changes(
+            &mut self.fs_states, fs::read_dir(&conf.state_dir)?)?
+            .into_iter()
+            .map(StateEvent::Written)
+            .collect();
 
-    book.extend_from_slice(cont);
+        for path in before {
+            if !self.fs_states.contains_key(&path) {
+                // put it back so the Removed arm sends it
+                let _ = self.fs_states.insert(path.clone(), FileDigest::dir());
+                events.push(StateEvent::Removed(path));
+            }
+        }
 
-    for _ in 0..num_reads {
-        rnb = qrx.read(rbuf)?; 
-        qrx.write(RECV_SEQ)?;
-        book.extend_from_slice(&rbuf[..rnb]);
+        return Ok(events);
     }
+    
+    // only public so I don't have to make another test module
+    // inside this one.
+    /// returns a vector of PathBuf's which have been changed
+    /// inside of the conf.state_dir fields indicated directory
+    /// which is monitored recursively. Only used at startup
+    /// and when the watcher loses events.
+    pub fn state_fs_changes(
+        fs_states: &mut HashMap<PathBuf, FileDigest>,
+        read_dir: ReadDir, 
+    ) -> DRes<Vec<PathBuf>> {
+        let mut current_files = HashSet::new();
+        let fupdates = Self::walk_changes(
+            fs_states, read_dir, &mut current_files)?;
 
-    fs::write(&format!("{}/{}", conf.book_dir, bname), book)?; 
-
-    return Ok(());
-}
+        fs_states.retain(|key, _| current_files.contains(key));
+        return Ok(fupdates);
+    }
 
-fn get_state_fs(
-    qrx: &mut QrexecClient,
-    conf: &Conf,
-    rbuf: &mut [u8; BLEN],
-) -> DRes<()> {
-    let mut nb; 
+    fn walk_changes(
+        fs_states: &mut HashMap<PathBuf, FileDigest>,
+        read_dir: ReadDir, 
+        current_files: &mut HashSet<PathBuf>,
+    ) -> DRes<Vec<PathBuf>> {
+        let mut fupdates = vec!();
+        for entry in read_dir {
+            let fpath = entry?.path();
+            if Self::record_state(fs_states, &fpath)? {
+                fupdates.push(fpath.clone());
+            }
 
-    qrx.write(GET_SFILES)?;
-    nb = qrx.read(rbuf)?;
-    qrx.write(RECV_SEQ)?;
+            if fpath.is_dir() {
+                fupdates.extend(Self::walk_changes(
+                    fs_states, fs::read_dir(&fpath)?, current_files)?);
+            }
 
-    if rbuf[..nb].starts_with(NONE) {
-        return Ok(()); 
+            let _ = current_files.insert(fpath);
+        }
+    
+        return Ok(fupdates);
     }
 
-    let id = find_delim(&rbuf[..nb], b':')
-        .ok_or(anyhow!(MSG_FORMAT_ERR))?;
-
-    let mut num_files = u32::from_ne_bytes(
-        rbuf[(id + 1)..nb].try_into()?);
+    /// stores the current digest of fpath, returns true if
+    /// its contents differ from the previously stored digest.
+    /// directories all share one digest so they only count
+    /// as changed when they first show up.
+    fn record_state(
+        fs_states: &mut HashMap<PathBuf, FileDigest>,
+        fpath: &Path,
+    ) -> DRes<bool> {
+        // only ever this sides own, see write_state_file
+        if is_partial(fpath) {
+            return Ok(false);
+        }
 
-    while num_files != 0 {
-        nb = qrx.read(rbuf)?;
-        recv_file(qrx, conf, rbuf, nb)?;
-        qrx.write(RECV_SEQ)?;
+        let meta = fs::metadata(fpath)?;
+        if fs_states.get(fpath).is_some_and(|old| old.same_stat(&meta)) {
+            return Ok(false);
+        }
 
-        num_files -= 1; 
+        let digest = FileDigest::of(fpath)?;
+        let changed = fs_states.get(fpath)
+            .is_none_or(|old| old.hash != digest.hash);
+        let _ = fs_states.insert(fpath.to_owned(), digest);
+        return Ok(changed);
     }
+}
 
-    return Ok(());
+fn rel_state_path(conf: &Conf, fpath: &Path) -> DRes<PathBuf> {
+    return match fpath.strip_prefix(&conf.state_dir) {
+        Ok(rel) => Ok(rel.to_owned()),
+        Err(_) => Err(QzbError::PathSafety {
+            path: fpath.to_owned(),
+            reason: "outside of the state dir",
+        }),
+    };
+}
+
+fn touches_db(event: &StateEvent) -> bool {
+    return match event {
+        StateEvent::Written(path) | StateEvent::Removed(path) => is_db_file(path),
+        StateEvent::Renamed { from, to } => is_db_file(from) || is_db_file(to),
+    };
 }
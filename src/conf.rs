@@ -1,15 +1,212 @@
-use std::{fs, io};
-use crate::shared_consts::*;
+use std::{env, fs, io, collections::HashMap, path::Path};
+use crate::{
+    shared_consts::*,
+    error::QzbError,
+    sanitize::resolve_name,
+    book_index::DocType,
+};
 use serde::{Serialize, Deserialize};
 use serde_yaml;
-use anyhow::anyhow;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Conf {
     pub state_dir: String,
     pub book_dir: String, 
     pub model: String,
     pub target_vm: String,
+    /// has to match zathuras own database setting,
+    /// both sides need the same value.
+    #[serde(default)]
+    pub state_backend: StateBackend,
+    /// server only, clients only get the zathura state
+    /// of the books they requested instead of all of it.
+    /// That leaves out every state file but bookmarks and
+    /// history, i.e. input-history.
+    #[serde(default)]
+    pub slice_state: bool,
+    #[serde(default)]
+    pub limits: Limits,
+    /// server only, what each calling vm may do keyed by
+    /// the QREXEC_REMOTE_DOMAIN qrexec runs the server
+    /// under, vms that aren't listed get default_policy.
+    #[serde(default)]
+    pub policies: HashMap<String, Policy>,
+    #[serde(default)]
+    pub default_policy: Policy,
+    /// server only, see Namespaces.
+    #[serde(default)]
+    pub namespaces: Namespaces,
+    /// client only, sent in the HELLO to pick a state
+    /// namespace on the server, see Namespaces.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// client only, books of any other format are left
+    /// out of the listing.
+    #[serde(default = "default_book_formats")]
+    pub book_formats: Vec<DocType>,
+    /// client only, mounts book_dir as a fuse filesystem
+    /// of the servers book index and keeps the fetched
+    /// books in this directory, see book_fs.rs. Needs a
+    /// build with the fuse feature.
+    #[serde(default)]
+    pub fuse_cache: Option<String>,
+    /// client only, fetches a book whenever its placeholder
+    /// is opened, for zathura builds that don't write to
+    /// the notify socket. See open_watch.rs.
+    #[serde(default)]
+    pub watch_opens: bool,
+    /// server only, the book index is kept in this file
+    /// between sessions so that only books whose size or
+    /// mtime changed are hashed again, see book_index.rs.
+    /// Defaults to INDEX_CACHE_PATH below $HOME.
+    #[serde(default = "default_index_cache")]
+    pub index_cache: Option<String>,
+}
+
+fn default_book_formats() -> Vec<DocType> {
+    return DocType::SUPPORTED.to_vec();
+}
+
+fn default_index_cache() -> Option<String> {
+    return env::var("HOME").ok()
+        .map(|home| format!("{home}/{INDEX_CACHE_PATH}"));
+}
+
+/// the server can keep a separate state dir per calling
+/// vm, or per profile a client names in its HELLO. A
+/// namespace named SHARED_NAMESPACE is conf.state_dir,
+/// every other one is a subdirectory of dir.
+///
+/// a vm listed in vms always gets its namespace, the
+/// profile is only looked up for other vms. Profiles are
+/// picked by the client so they keep the reading history
+/// of unrelated qubes apart but are no security boundary,
+/// map the calling vm for that.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Namespaces {
+    pub dir: Option<String>,
+    /// calling vm -> namespace
+    pub vms: HashMap<String, String>,
+    /// profile -> namespace
+    pub profiles: HashMap<String, String>,
+    /// the namespace of every other client
+    pub default: String,
+}
+
+pub const SHARED_NAMESPACE: &str = "shared";
+
+impl Default for Namespaces {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            vms: HashMap::new(),
+            profiles: HashMap::new(),
+            default: SHARED_NAMESPACE.to_owned(),
+        }
+    }
+}
+
+/// the operations a calling vm is allowed, i.e.
+/// upload_state: false keeps the vault read only.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    pub list_books: bool,
+    pub fetch_books: bool,
+    pub fetch_state: bool,
+    /// state files, removals, renames and database tables
+    pub upload_state: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            list_books: true,
+            fetch_books: true,
+            fetch_state: true,
+            upload_state: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    ListBooks,
+    FetchBooks,
+    FetchState,
+    UploadState,
+}
+
+impl Policy {
+    pub fn allows(&self, op: Operation) -> bool {
+        return match op {
+            Operation::ListBooks => self.list_books,
+            Operation::FetchBooks => self.fetch_books,
+            Operation::FetchState => self.fetch_state,
+            Operation::UploadState => self.upload_state,
+        };
+    }
+}
+
+/// bounds on everything received from the peer, enforced
+/// on both sides since either one can be compromised.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// in bytes, applies to books and state files alike.
+    pub max_file_size: u64,
+    /// in bytes, the whole state dir after a write.
+    pub max_state_size: u64,
+    /// files and directories in the state dir, also
+    /// bounds the number of book names accepted.
+    pub max_files: u64,
+    /// in bytes, of a path relative to its directory.
+    pub max_path_len: u64,
+    pub max_path_depth: u64,
+    /// across all tables of a single DB_TABLES message.
+    pub max_db_rows: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_file_size: 512 * 1024 * 1024,
+            max_state_size: 1024 * 1024 * 1024,
+            max_files: 10_000,
+            max_path_len: 1024,
+            max_path_depth: 16,
+            max_db_rows: 100_000,
+        }
+    }
+}
+
+impl Limits {
+    /// the largest frame a peer within these limits needs,
+    /// a whole file or a manifest listing max_files paths,
+    /// plus room for the rest of the message.
+    pub fn max_frame_len(&self) -> u64 {
+        let slack = self.max_path_len.saturating_add(64);
+        let manifest = self.max_files.saturating_mul(slack);
+        return self.max_file_size.max(manifest).saturating_add(slack);
+    }
+
+    /// the largest frame of any message that carries
+    /// neither a file nor a listing, two paths at most.
+    pub fn max_msg_len(&self) -> u64 {
+        return self.max_path_len.saturating_mul(2).saturating_add(64);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateBackend {
+    /// zathuras plain database, state files are
+    /// copied and merged as files.
+    #[default]
+    Plain,
+    /// zathuras sqlite database, synced row by row.
+    Sqlite,
 }
 
 impl Conf {
@@ -21,9 +218,43 @@ impl Conf {
         return Ok(conf);
     } 
 
+    pub fn policy_for(&self, remote_domain: &str) -> &Policy {
+        return self.policies.get(remote_domain).unwrap_or(&self.default_policy);
+    }
+
+    /// the state dir of a session with remote_domain, see
+    /// Namespaces. Creates it if it doesn't exist yet.
+    pub fn namespace_for(&self, remote_domain: &str, profile: Option<&str>) -> DRes<String> {
+        let ns = &self.namespaces;
+        let name = match (ns.vms.get(remote_domain), profile) {
+            (Some(name), _) => name,
+            (None, Some(profile)) => ns.profiles.get(profile).unwrap_or(&ns.default),
+            (None, None) => &ns.default,
+        };
+
+        if name == SHARED_NAMESPACE {
+            return Ok(self.state_dir.clone());
+        }
+
+        let Some(dir) = &ns.dir else {
+            return Err(QzbError::Config(format!("namespace {name:?} without namespaces.dir")));
+        };
+        let state_dir = resolve_name(Path::new(dir), name)?;
+        fs::create_dir_all(&state_dir)?;
+        return Ok(state_dir.to_str()
+            .ok_or(QzbError::Config(format!("namespaces.dir {dir:?} is not utf8")))?
+            .to_owned());
+    }
+
+    /// where fetched books are written, book_dir itself
+    /// is the mountpoint in fuse mode.
+    pub fn local_book_dir(&self) -> &str {
+        return self.fuse_cache.as_deref().unwrap_or(&self.book_dir);
+    }
+
     fn path() -> DRes<String> {
         if !fs::exists(CONF_PATH)? {
-            Err(anyhow!(CONF_EXISTS_ERR))?
+            Err(QzbError::Config(format!("{CONF_PATH} does not exist")))?
         } else {
             return Ok(CONF_PATH.to_owned());
         }
@@ -32,6 +263,12 @@ impl Conf {
     fn init_dirs(conf: &Conf) -> io::Result<()> {
         fs::create_dir_all(&conf.book_dir)?;
         fs::create_dir_all(&conf.state_dir)?;
+        if let Some(cache) = &conf.fuse_cache {
+            fs::create_dir_all(cache)?;
+        }
+        if let Some(parent) = conf.index_cache.as_deref().and_then(|cache| Path::new(cache).parent()) {
+            fs::create_dir_all(parent)?;
+        }
 
         return Ok(()); 
     }
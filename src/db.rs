@@ -0,0 +1,260 @@
+// zathuras sqlite backend keeps everything in a single
+// bookmarks.sqlite, copying that file while zathura has
+// it open can tear it, so it is synced row by row
+// through sqlite itself instead.
+//
+// every upload is applied as a three-way merge against
+// the tables the uploading client last saw (base):
+// rows it changed or added are upserted, rows it
+// removed are deleted unless they changed since.
+
+use crate::{
+    shared_consts::*,
+    error::{QzbError, ProtocolError},
+    merge::is_book_entry,
+    conf::{Conf, StateBackend},
+};
+use std::{
+    time::Duration,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+use rusqlite::{Connection, params_from_iter, types::Value};
+
+pub type DbValue = Value;
+
+pub const DB_FNAME: &str = "bookmarks.sqlite";
+pub const SYNCED_TABLES: &[&str] = &["bookmarks", "fileinfo", "jumplist"];
+/// zathuras widest table has 11, see SCHEMA.
+pub const MAX_DB_COLUMNS: u64 = 32;
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the schema zathura creates, only used when the
+/// database doesn't exist yet on this side.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS bookmarks (
+        file TEXT,
+        id TEXT,
+        page INTEGER,
+        hadj_ratio FLOAT,
+        vadj_ratio FLOAT,
+        PRIMARY KEY(file, id));
+    CREATE TABLE IF NOT EXISTS fileinfo (
+        file TEXT PRIMARY KEY,
+        page INTEGER,
+        offset INTEGER,
+        zoom FLOAT,
+        rotation INTEGER,
+        pages_per_row INTEGER,
+        first_page_column TEXT,
+        position_x FLOAT,
+        position_y FLOAT,
+        time TIMESTAMP,
+        page_right_to_left INTEGER);
+    CREATE TABLE IF NOT EXISTS jumplist (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        file TEXT,
+        page INTEGER,
+        hadj_ratio FLOAT,
+        vadj_ratio FLOAT);";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbTable {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<DbValue>>,
+}
+
+impl DbTable {
+    /// indices of the columns that identify a row, jumplist
+    /// ids are local autoincrement values so its rows are
+    /// only identified by their whole contents.
+    fn key_idxs(&self) -> Vec<usize> {
+        let keys: &[&str] = match self.name.as_str() {
+            "bookmarks" => &["file", "id"],
+            "fileinfo" => &["file"],
+            _ => return (0..self.columns.len()).collect(),
+        };
+
+        return self.columns.iter()
+            .enumerate()
+            .filter(|(_, col)| keys.contains(&col.as_str()))
+            .map(|(idx, _)| idx)
+            .collect();
+    }
+
+    fn find_key(&self, row: &[DbValue], key_idxs: &[usize]) -> Option<&Vec<DbValue>> {
+        return self.rows.iter()
+            .find(|other| key_idxs.iter().all(|idx| other[*idx] == row[*idx]));
+    }
+}
+
+pub fn db_path(conf: &Conf) -> PathBuf {
+    return Path::new(&conf.state_dir).join(DB_FNAME);
+}
+
+/// the database itself and the journal files sqlite
+/// keeps next to it, none of them are copied as files.
+pub fn is_db_file(path: &Path) -> bool {
+    let Some(fname) = path.file_name().and_then(OsStr::to_str) else {
+        return false;
+    };
+
+    return fname == DB_FNAME
+        || ["-wal", "-shm", "-journal"].iter()
+            .any(|ext| fname.strip_prefix(DB_FNAME) == Some(*ext));
+}
+
+/// false for the database files when the sqlite backend
+/// is in use, those never go through the state file sync.
+pub fn synced_as_file(conf: &Conf, path: &Path) -> bool {
+    return conf.state_backend == StateBackend::Plain || !is_db_file(path);
+}
+
+pub fn open(path: &Path) -> DRes<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.execute_batch(SCHEMA)?;
+    return Ok(conn);
+}
+
+pub fn read_tables(conn: &Connection) -> DRes<Vec<DbTable>> {
+    let mut tables = vec!();
+    for name in SYNCED_TABLES {
+        let mut stmt = conn.prepare(&format!("SELECT * FROM \"{name}\""))?;
+        let columns: Vec<String> = stmt.column_names()
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        // local row ids mean nothing on the other side
+        let skip = if *name == "jumplist" {
+            columns.iter().position(|col| col == "id")
+        } else {
+            None
+        };
+
+        let mut rows = vec!();
+        let mut query = stmt.query([])?;
+        while let Some(row) = query.next()? {
+            let mut vals = vec!();
+            for idx in (0..columns.len()).filter(|idx| Some(*idx) != skip) {
+                vals.push(row.get::<_, DbValue>(idx)?);
+            }
+            rows.push(vals);
+        }
+
+        let columns = columns.into_iter()
+            .enumerate()
+            .filter(|(idx, _)| Some(*idx) != skip)
+            .map(|(_, col)| col)
+            .collect();
+        tables.push(DbTable { name: name.to_string(), columns, rows });
+    }
+
+    return Ok(tables);
+}
+
+/// applies theirs on top of the local database, see the
+/// top of this file. Table and column names come from
+/// the peer so anything zathura doesn't have is refused,
+/// as is anything that grows the database past max_size.
+pub fn apply_tables(
+    conn: &mut Connection,
+    base: &[DbTable],
+    theirs: &[DbTable],
+    max_size: u64,
+) -> DRes<()> {
+    let tx = conn.transaction()?;
+    for table in theirs {
+        if !SYNCED_TABLES.contains(&table.name.as_str()) {
+            Err(ProtocolError::DbTable(table.name.clone()))?;
+        }
+
+        let local_cols = table_columns(&tx, &table.name)?;
+        if table.columns.iter().any(|col| !local_cols.contains(col))
+            || table.rows.iter().any(|row| row.len() != table.columns.len()) {
+            Err(ProtocolError::DbTable(table.name.clone()))?;
+        }
+
+        let key_idxs = table.key_idxs();
+        let base = base.iter()
+            .find(|x| x.name == table.name && x.columns == table.columns);
+
+        for row in &table.rows {
+            if base.is_some_and(|base| base.rows.contains(row)) {
+                continue;
+            }
+            delete_matching(&tx, table, row, &key_idxs)?;
+            insert_row(&tx, table, row)?;
+        }
+
+        let Some(base) = base else { continue; };
+        let all_idxs: Vec<usize> = (0..table.columns.len()).collect();
+        for row in &base.rows {
+            if table.find_key(row, &key_idxs).is_none() {
+                // only if nobody changed it in the meantime
+                delete_matching(&tx, table, row, &all_idxs)?;
+            }
+        }
+    }
+
+    // dropping tx rolls the whole upload back
+    let size: u64 = tx.query_row(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        [], |row| row.get(0))?;
+    if size > max_size {
+        Err(QzbError::LimitExceeded { what: "database size", limit: max_size })?;
+    }
+
+    tx.commit()?;
+    return Ok(());
+}
+
+/// keeps only the rows of books, see merge::slice_bytes.
+/// Every synced table has a file column.
+pub fn slice_tables(tables: &mut [DbTable], books: &[String]) {
+    for table in tables {
+        let Some(file_idx) = table.columns.iter().position(|col| col == "file") else {
+            table.rows.clear();
+            continue;
+        };
+
+        table.rows.retain(|row| match &row[file_idx] {
+            DbValue::Text(file) => is_book_entry(file, books),
+            _ => false,
+        });
+    }
+}
+
+fn table_columns(conn: &Connection, table: &str) -> DRes<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info(\"{table}\")"))?;
+    let cols = stmt.query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<String>, _>>()?;
+    return Ok(cols);
+}
+
+fn delete_matching(
+    conn: &Connection,
+    table: &DbTable,
+    row: &[DbValue],
+    idxs: &[usize],
+) -> DRes<()> {
+    let conds: Vec<String> = idxs.iter()
+        .map(|idx| format!("\"{}\" IS ?", table.columns[*idx]))
+        .collect();
+    let sql = format!("DELETE FROM \"{}\" WHERE {}", table.name, conds.join(" AND "));
+    let _ = conn.execute(&sql, params_from_iter(idxs.iter().map(|idx| &row[*idx])))?;
+    return Ok(());
+}
+
+fn insert_row(conn: &Connection, table: &DbTable, row: &[DbValue]) -> DRes<()> {
+    let cols: Vec<String> = table.columns.iter()
+        .map(|col| format!("\"{col}\""))
+        .collect();
+    let holders = vec!("?"; cols.len()).join(", ");
+    let sql = format!(
+        "INSERT INTO \"{}\" ({}) VALUES ({holders})",
+        table.name, cols.join(", "));
+    let _ = conn.execute(&sql, params_from_iter(row.iter()))?;
+    return Ok(());
+}
@@ -0,0 +1,57 @@
+use std::{
+    fs::{self, Metadata},
+    io,
+    path::Path,
+    os::unix::fs::MetadataExt,
+};
+
+pub const HASH_LEN: usize = 32;
+pub type Hash = [u8; HASH_LEN];
+
+/// what we remember about a state file instead of its
+/// contents, size and mtime only decide whether the
+/// file has to be hashed again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileDigest {
+    pub hash: Hash,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+}
+
+impl FileDigest {
+    /// directories carry no contents, they are
+    /// all represented by the same digest.
+    pub fn dir() -> Self {
+        return Self { hash: [0u8; HASH_LEN], size: 0, mtime: 0, mtime_nsec: 0 };
+    }
+
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let meta = fs::metadata(path)?;
+        if meta.is_dir() {
+            return Ok(Self::dir());
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        let _ = hasher.update_reader(fs::File::open(path)?)?;
+        return Ok(Self {
+            hash: *hasher.finalize().as_bytes(),
+            size: meta.size(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+        });
+    }
+
+    /// true if meta still describes the file this digest
+    /// was taken of, i.e. rehashing can be skipped.
+    pub fn same_stat(&self, meta: &Metadata) -> bool {
+        return !meta.is_dir()
+            && self.size == meta.size()
+            && self.mtime == meta.mtime()
+            && self.mtime_nsec == meta.mtime_nsec();
+    }
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> Hash {
+    return *blake3::hash(bytes).as_bytes();
+}
@@ -0,0 +1,169 @@
+// every error this crate returns, split by where it
+// came from so that callers can tell the errors a
+// session survives apart from the ones that end it.
+
+use std::{
+    io,
+    array::TryFromSliceError,
+    num::TryFromIntError,
+    path::PathBuf,
+    str::Utf8Error,
+};
+use crate::{
+    protocol::Message,
+    conf::Operation,
+};
+use thiserror::Error;
+
+/// in bytes, longer reasons are cut short so that an
+/// ERROR always fits protocol::ERROR_FRAME_LEN.
+pub const MAX_REASON_LEN: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum QzbError {
+    #[error("protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
+    #[error("the peer closed the pipe in the middle of an exchange")]
+    PeerClosed,
+    /// an ERROR the peer answered with, see protocol.rs
+    #[error("the peer reported {code:?}: {reason}")]
+    Remote { code: ErrorCode, reason: String },
+    #[error("the book does not exist in the configured book directory")]
+    BookUnavailable,
+    /// a bare file name that more than one book has
+    #[error("{name} is ambiguous, it could be any of {matches:?}")]
+    AmbiguousBook { name: String, matches: Vec<PathBuf> },
+    #[error("configuration error: {0}")]
+    Config(String),
+    #[error("rejected path {path:?}: {reason}")]
+    PathSafety { path: PathBuf, reason: &'static str },
+    /// see conf::Policy
+    #[error("the policy for this vm does not allow {0:?}")]
+    Denied(Operation),
+    /// see conf::Limits
+    #[error("{what} exceeds the configured limit of {limit}")]
+    LimitExceeded { what: &'static str, limit: u64 },
+    #[error("database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    #[error("qrexec error: {0}")]
+    Qrexec(String),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    /// a bug on this side, not caused by the peer
+    #[error("internal error: {0}")]
+    Internal(&'static str),
+}
+
+impl QzbError {
+    /// true if the session can go on after this error,
+    /// PeerClosed needs a new session but isn't fatal
+    /// to the client either. Neither is a local io error,
+    /// a rejected path or size, the next session starts
+    /// over from a fresh listing.
+    pub fn is_recoverable(&self) -> bool {
+        return matches!(self,
+            Self::BookUnavailable
+            | Self::Remote { .. }
+            | Self::PeerClosed
+            | Self::Io(_)
+            | Self::PathSafety { .. }
+            | Self::LimitExceeded { .. });
+    }
+
+    /// what the peer is told about this error, an io
+    /// error only tells it what kind of error it was.
+    pub fn to_message(&self) -> Message {
+        let (code, mut reason) = match self {
+            Self::Protocol(e) => (ErrorCode::Protocol, e.to_string()),
+            Self::BookUnavailable | Self::AmbiguousBook { .. } => {
+                (ErrorCode::BookNotFound, self.to_string())
+            }
+            Self::PathSafety { .. } => (ErrorCode::PathRejected, self.to_string()),
+            Self::Denied(_) => (ErrorCode::PermissionDenied, self.to_string()),
+            Self::LimitExceeded { .. } => (ErrorCode::LimitExceeded, self.to_string()),
+            Self::Io(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                (ErrorCode::PermissionDenied, e.kind().to_string())
+            }
+            Self::Io(e) => (ErrorCode::Io, e.kind().to_string()),
+            Self::Remote { code, reason } => (*code, reason.clone()),
+            _ => (ErrorCode::Internal, self.to_string()),
+        };
+
+        reason.truncate(reason.floor_char_boundary(MAX_REASON_LEN));
+        return Message::Error { code, reason };
+    }
+}
+
+/// the kind of error an ERROR message reports,
+/// encoded as a single byte on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Protocol = 0,
+    BookNotFound = 1,
+    PermissionDenied = 2,
+    PathRejected = 3,
+    Io = 4,
+    Internal = 5,
+    LimitExceeded = 6,
+}
+
+impl ErrorCode {
+    pub fn from_u8(code: u8) -> Result<Self, ProtocolError> {
+        return Ok(match code {
+            0 => Self::Protocol,
+            1 => Self::BookNotFound,
+            2 => Self::PermissionDenied,
+            3 => Self::PathRejected,
+            4 => Self::Io,
+            5 => Self::Internal,
+            6 => Self::LimitExceeded,
+            _ => Err(ProtocolError::MsgFormat)?,
+        });
+    }
+}
+
+/// ways the peer can break the wire protocol, these
+/// travel back to it as the reason of an ERROR.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ProtocolError {
+    #[error("the read bytes have incorrect formatting")]
+    MsgFormat,
+    #[error("a string on the wire is not utf8")]
+    InvalidEnc,
+    #[error("a length does not fit the wire format")]
+    TooLarge,
+    #[error("the message tag does not match any known message")]
+    UnknownMsg,
+    #[error("the message is valid but not expected at this point in the exchange")]
+    UnexpectedMsg,
+    #[error("the peer did not start the connection with HELLO")]
+    Handshake,
+    #[error("the client and server protocol versions differ: local {local}, remote {remote}")]
+    VersionMismatch { local: u32, remote: u32 },
+    #[error("the database table {0} or its columns don't match the ones zathura creates")]
+    DbTable(String),
+}
+
+impl From<Utf8Error> for QzbError {
+    fn from(_: Utf8Error) -> Self {
+        return Self::Protocol(ProtocolError::InvalidEnc);
+    }
+}
+
+impl From<TryFromIntError> for QzbError {
+    fn from(_: TryFromIntError) -> Self {
+        return Self::Protocol(ProtocolError::TooLarge);
+    }
+}
+
+impl From<TryFromSliceError> for QzbError {
+    fn from(_: TryFromSliceError) -> Self {
+        return Self::Protocol(ProtocolError::MsgFormat);
+    }
+}
+
+impl From<serde_yaml::Error> for QzbError {
+    fn from(e: serde_yaml::Error) -> Self {
+        return Self::Config(e.to_string());
+    }
+}
@@ -0,0 +1,173 @@
+// ~~~~~~~ EVENT LOOP ~~~~~~~ //
+//
+// the client session waits on everything at once
+// instead of polling each source in turn: the notify
+// socket and its callers, the state dir watcher, the
+// fetch queue, the qrexec pipe and the shutdown signal.
+// Every source is a file descriptor registered with an
+// epoll instance under a token, wait returns the tokens
+// of the ones that became readable.
+//
+// epoll is level triggered here, a source stays ready
+// until whatever it has queued has been read.
+//
+
+use crate::watcher::poll_readable;
+use std::{
+    io::{self, ErrorKind::*},
+    mem,
+    ptr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::Duration,
+};
+
+const MAX_EVENTS: usize = 16;
+/// the signals that end the client, a closed terminal
+/// or logging out of the vm sends SIGHUP.
+pub const SHUTDOWN_SIGNALS: [libc::c_int; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+
+pub struct EventLoop {
+    epfd: OwnedFd,
+}
+
+impl EventLoop {
+    pub fn new() -> io::Result<Self> {
+        let raw = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // raw is a fresh descriptor nothing else owns
+        return Ok(Self { epfd: unsafe { OwnedFd::from_raw_fd(raw) } });
+    }
+
+    /// wakes wait with token whenever fd is readable or
+    /// hung up. A closed fd drops out on its own.
+    pub fn add(&self, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            u64: token,
+        };
+
+        let ret = unsafe {
+            libc::epoll_ctl(self.epfd.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut event)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        return Ok(());
+    }
+
+    /// blocks until at least one fd is ready or timeout ran
+    /// out, None waits forever. The tokens of the ready fds
+    /// replace the contents of ready, each one only once.
+    pub fn wait(&self, ready: &mut Vec<u64>, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout_ms = match timeout {
+            Some(timeout) => timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX),
+            None => -1,
+        };
+
+        ready.clear();
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        // events outlives the call and maxevents matches its length.
+        let ret = unsafe {
+            libc::epoll_wait(
+                self.epfd.as_raw_fd(),
+                events.as_mut_ptr(),
+                MAX_EVENTS as libc::c_int,
+                timeout_ms)
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == Interrupted {
+                return Ok(());
+            }
+            return Err(err);
+        }
+
+        for event in &events[..ret as usize] {
+            let token = event.u64;
+            if !ready.contains(&token) {
+                ready.push(token);
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+impl AsRawFd for EventLoop {
+    /// readable whenever one of its fds is, so an event
+    /// loop can be added to another one.
+    fn as_raw_fd(&self) -> RawFd {
+        return self.epfd.as_raw_fd();
+    }
+}
+
+/// SHUTDOWN_SIGNALS delivered through a signalfd instead
+/// of killing the process, so the loop gets to flush
+/// the state and remove the notify socket first.
+pub struct ShutdownSignal {
+    fd: OwnedFd,
+}
+
+impl ShutdownSignal {
+    /// blocks SHUTDOWN_SIGNALS in the calling thread, only
+    /// threads spawned after this inherit the mask so it
+    /// has to come before any of them. A signal that still
+    /// reaches an older thread kills the process as before.
+    pub fn new() -> io::Result<Self> {
+        let mut set = unsafe { mem::zeroed::<libc::sigset_t>() };
+        // set lives on the stack for all of these calls
+        unsafe {
+            let _ = libc::sigemptyset(&mut set);
+            for sig in SHUTDOWN_SIGNALS {
+                let _ = libc::sigaddset(&mut set, sig);
+            }
+        }
+
+        let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+
+        let raw = unsafe { libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        return Ok(Self { fd: unsafe { OwnedFd::from_raw_fd(raw) } });
+    }
+
+    /// the signal that arrived since the last call if any,
+    /// never blocks.
+    pub fn received(&self) -> io::Result<Option<u32>> {
+        let mut info = unsafe { mem::zeroed::<libc::signalfd_siginfo>() };
+        let len = mem::size_of::<libc::signalfd_siginfo>();
+        let nb = unsafe {
+            libc::read(self.fd.as_raw_fd(), (&raw mut info).cast(), len)
+        };
+        if nb < 0 {
+            let err = io::Error::last_os_error();
+            if matches!(err.kind(), WouldBlock | Interrupted) {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+
+        return Ok(Some(info.ssi_signo));
+    }
+
+    /// thread::sleep that wakes up early for a signal.
+    pub fn sleep(&self, dur: Duration) -> io::Result<Option<u32>> {
+        let _ = poll_readable(self.fd.as_raw_fd(), dur)?;
+        return self.received();
+    }
+}
+
+impl AsRawFd for ShutdownSignal {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.fd.as_raw_fd();
+    }
+}
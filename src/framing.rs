@@ -0,0 +1,135 @@
+// ~~~~~~~ FRAMING ~~~~~~~ //
+//
+// every message crossing qrexec is one frame:
+//
+// <len: u64 little endian><len bytes of payload>
+//
+// a qrexec pipe can split or merge writes however
+// it likes, so both directions loop until the exact
+// number of bytes has been moved instead of trusting
+// a single read / write call.
+//
+
+use crate::{
+    shared_consts::*,
+    error::QzbError,
+};
+use std::io;
+use qrexec_binds::QIO;
+
+pub const FRAME_HEADER_LEN: usize = 8;
+
+pub fn write_frame<T: QIO>(qrx: &mut T, payload: &[u8]) -> DRes<()> {
+    let len: u64 = payload.len().try_into()?;
+    write_all(qrx, &len.to_le_bytes())?;
+    write_all(qrx, payload)?;
+    return Ok(());
+}
+
+/// reads one frame, buf is only used as scratch space.
+/// returns None if the peer closed the pipe cleanly
+/// before sending a new frame, a close in the middle
+/// of a frame is an error. max_len gets the first byte
+/// of the payload, the message tag, a frame longer than
+/// what it returns is refused before the rest is read.
+/// The pipe can't be used for another frame after that.
+pub fn read_frame<T: QIO>(
+    qrx: &mut T,
+    buf: &mut [u8; BLEN],
+    max_len: impl Fn(u8) -> u64,
+) -> DRes<Option<Vec<u8>>> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    if !read_exact(qrx, &mut header)? {
+        return Ok(None);
+    }
+
+    let len = u64::from_le_bytes(header);
+    let mut payload = vec!();
+    if len > 0 {
+        let mut tag = [0u8];
+        if !read_exact(qrx, &mut tag)? {
+            Err(QzbError::PeerClosed)?;
+        }
+
+        let max_len = max_len(tag[0]);
+        if len > max_len {
+            Err(QzbError::LimitExceeded { what: "frame length", limit: max_len })?;
+        }
+        payload.push(tag[0]);
+    }
+
+    let len: usize = len.try_into()?;
+    while payload.len() != len {
+        let want = buf.len().min(len - payload.len());
+        let nb = qrx.read(&mut buf[..want])?;
+        if nb == 0 {
+            Err(QzbError::PeerClosed)?;
+        }
+        payload.extend_from_slice(&buf[..nb]);
+    }
+
+    return Ok(Some(payload));
+}
+
+/// write_frame for a plain socket, see notify.rs.
+pub fn frame(payload: &[u8]) -> DRes<Vec<u8>> {
+    let len: u64 = payload.len().try_into()?;
+    return Ok([&len.to_le_bytes()[..], payload].concat());
+}
+
+/// read_frame for bytes that arrive bit by bit on a non
+/// blocking socket, removes the first frame from buf if
+/// all of it is there.
+pub fn take_frame(buf: &mut Vec<u8>, max_len: u64) -> DRes<Option<Vec<u8>>> {
+    let Some(header) = buf.first_chunk::<FRAME_HEADER_LEN>() else {
+        return Ok(None);
+    };
+
+    let len = u64::from_le_bytes(*header);
+    if len > max_len {
+        Err(QzbError::LimitExceeded { what: "frame length", limit: max_len })?;
+    }
+
+    let end = FRAME_HEADER_LEN + usize::try_from(len)?;
+    if buf.len() < end {
+        return Ok(None);
+    }
+
+    let payload = buf[FRAME_HEADER_LEN..end].to_vec();
+    let _ = buf.drain(..end);
+    return Ok(Some(payload));
+}
+
+fn write_all<T: QIO>(qrx: &mut T, mut bytes: &[u8]) -> DRes<()> {
+    while !bytes.is_empty() {
+        let nb = match qrx.write(bytes) {
+            Ok(nb) => nb,
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Err(QzbError::PeerClosed)?,
+            Err(e) => Err(e)?,
+        };
+        if nb == 0 {
+            Err(QzbError::PeerClosed)?;
+        }
+        bytes = &bytes[nb..];
+    }
+
+    return Ok(());
+}
+
+/// fills buf completely, returns false if the pipe was
+/// closed before the first byte arrived.
+fn read_exact<T: QIO>(qrx: &mut T, buf: &mut [u8]) -> DRes<bool> {
+    let mut filled = 0usize;
+    while filled != buf.len() {
+        let nb = qrx.read(&mut buf[filled..])?;
+        if nb == 0 {
+            if filled == 0 {
+                return Ok(false);
+            }
+            Err(QzbError::PeerClosed)?;
+        }
+        filled += nb;
+    }
+
+    return Ok(true);
+}
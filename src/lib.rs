@@ -0,0 +1,26 @@
+// every function ends in an explicit return
+#![allow(clippy::needless_return)]
+
+#[cfg(test)]
+mod test;
+#[cfg(test)]
+mod mem_qio;
+
+pub mod conf;
+pub mod error;
+mod shared_fn;
+pub mod shared_consts;
+mod sanitize;
+mod protocol;
+pub mod framing;
+pub mod notify;
+mod watcher;
+mod digest;
+mod merge;
+mod db;
+mod book_index;
+mod book_fs;
+mod open_watch;
+mod event_loop;
+pub mod client;
+pub mod server;
@@ -1,23 +1,15 @@
-#[cfg(test)]
-mod test;
+// every function ends in an explicit return
+#![allow(clippy::needless_return)]
 
-mod conf;
-mod shared_fn;
-mod shared_consts;
-mod client;
-mod server;
-
-use crate::{
+use qubes_zathura_bookmark::{
     client::client_main,
     server::server_main,
     shared_consts::*,
+    error::QzbError,
     conf::Conf,
 };
 use dbuggery::{err_append, append};
 
-const ERR_LOG_DIR_NAME: &str = "zathura-bookmark-service";
-const ERR_FNAME: &str = "errors.log";
-
 fn main() {
     let conf = Conf::new();
     err_append(
@@ -36,7 +28,7 @@ fn main() {
             ERR_FNAME,
             ERR_LOG_DIR_NAME),
         _ => append(
-            INVALID_MODEL_ERR,
+            QzbError::Config(format!("invalid model {:?}", conf.model)).to_string(),
             ERR_FNAME,
             ERR_LOG_DIR_NAME),
     };
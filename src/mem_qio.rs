@@ -0,0 +1,76 @@
+// in-process stand-in for a qrexec pipe so the
+// client and server halves can talk to each other
+// inside a single test without any Qubes VMs.
+
+use crate::{
+    shared_consts::*,
+    error::QzbError,
+    conf::Conf,
+    server::serve,
+};
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    thread,
+};
+use qrexec_binds::QIO;
+
+/// one end of a duplex socketpair, dropping
+/// it closes the pipe for the other end.
+pub struct MemQIO(UnixStream);
+
+impl MemQIO {
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (client, server) = UnixStream::pair()?;
+        return Ok((Self(client), Self(server)));
+    }
+}
+
+impl QIO for MemQIO {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        return self.0.read(buf);
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.0.write(buf);
+    }
+}
+
+// lets a test hand the pipe to a client::Requester
+// and still use it directly afterwards.
+impl QIO for &mut MemQIO {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        return self.0.read(buf);
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.0.write(buf);
+    }
+}
+
+/// the calling vm every test session runs as
+pub const TEST_DOMAIN: &str = "disp-test";
+
+/// runs server::serve with server_conf on its own thread
+/// and hands the client end of the pipe to client, the
+/// pipe is closed once client returns so the server
+/// session ends the same way it does in a real VM.
+pub fn run_session<F>(server_conf: Conf, client: F) -> DRes<()>
+where
+    F: FnOnce(&mut MemQIO) -> DRes<()>,
+{
+    let (mut client_end, server_end) = MemQIO::pair()?;
+    let server = thread::spawn(move || {
+        return serve(server_end, &server_conf, TEST_DOMAIN);
+    });
+
+    let client_res = client(&mut client_end);
+    drop(client_end);
+
+    let server_res = server.join()
+        .map_err(|_| QzbError::Internal("the server thread panicked"))?;
+
+    client_res?;
+    server_res?;
+    return Ok(());
+}
@@ -0,0 +1,165 @@
+// zathuras plain text database backend keeps its
+// bookmarks and history in GLib key files:
+//
+// [/path/to/book.pdf]
+// <bookmark id | setting>=<value>
+//
+// one group per book, so merging per (group, key)
+// entry lets two VMs add bookmarks to the same book
+// without overwriting each other.
+
+use std::{
+    ffi::OsStr,
+    path::Path,
+};
+
+pub const MERGED_FILES: &[&str] = &["bookmarks", "history"];
+
+/// true for the zathura state files merge knows
+/// how to handle, anything else is copied as is.
+pub fn is_mergeable(path: &Path) -> bool {
+    return path.file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|fname| MERGED_FILES.contains(&fname));
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyFile {
+    pub groups: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl KeyFile {
+    /// comments, blank lines and anything before the
+    /// first group are dropped, zathura writes none.
+    pub fn parse(raw: &str) -> Self {
+        let mut kf = Self::default();
+        for line in raw.lines() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.trim_end().ends_with(']') {
+                let line = line.trim_end();
+                kf.groups.push((line[1..(line.len() - 1)].to_owned(), vec!()));
+            } else if let (Some((key, val)), Some(group)) = (line.split_once('='), kf.groups.last_mut()) {
+                group.1.push((key.trim_end().to_owned(), val.trim_start().to_owned()));
+            }
+        }
+
+        return kf;
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut raw = String::new();
+        for (idx, (group, entries)) in self.groups.iter().enumerate() {
+            if idx != 0 {
+                raw.push('\n');
+            }
+
+            raw.push_str(&format!("[{group}]\n"));
+            for (key, val) in entries {
+                raw.push_str(&format!("{key}={val}\n"));
+            }
+        }
+
+        return raw;
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Vec<(String, String)>> {
+        return self.groups.iter()
+            .find(|(group, _)| group == name)
+            .map(|(_, entries)| entries);
+    }
+
+    pub fn get(&self, group: &str, key: &str) -> Option<&str> {
+        return self.group(group)?.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, val)| val.as_str());
+    }
+}
+
+/// per entry three-way merge, base is the version the
+/// uploading client last saw, ours is the current store
+/// and theirs is the upload. A side that left an entry
+/// as it was in base loses to the side that changed it,
+/// if both changed it theirs wins since it is newer.
+pub fn merge(base: &KeyFile, ours: &KeyFile, theirs: &KeyFile) -> KeyFile {
+    let mut merged = KeyFile::default();
+
+    let mut group_names: Vec<&str> = vec!();
+    for (group, _) in ours.groups.iter().chain(theirs.groups.iter()) {
+        if !group_names.contains(&group.as_str()) {
+            group_names.push(group);
+        }
+    }
+
+    for group in group_names {
+        let mut keys: Vec<&str> = vec!();
+        for entries in [ours.group(group), theirs.group(group)].into_iter().flatten() {
+            for (key, _) in entries {
+                if !keys.contains(&key.as_str()) {
+                    keys.push(key);
+                }
+            }
+        }
+
+        let mut entries = vec!();
+        for key in keys {
+            let base_val = base.get(group, key);
+            let ours_val = ours.get(group, key);
+            let theirs_val = theirs.get(group, key);
+
+            let val = if theirs_val == base_val {
+                ours_val
+            } else if ours_val == base_val {
+                theirs_val
+            } else {
+                theirs_val.or(ours_val)
+            };
+
+            if let Some(val) = val {
+                entries.push((key.to_owned(), val.to_owned()));
+            }
+        }
+
+        // an emptied group survives only if a side
+        // that isn't just echoing base still has it.
+        let in_base = base.group(group).is_some();
+        let in_ours = ours.group(group).is_some();
+        let in_theirs = theirs.group(group).is_some();
+        let keep_empty = (in_ours && in_theirs) || !in_base;
+        if !entries.is_empty() || keep_empty {
+            merged.groups.push((group.to_owned(), entries));
+        }
+    }
+
+    return merged;
+}
+
+/// true if path, a group name or a database file column,
+/// belongs to one of books. Compared by basename since
+/// every VM keeps its books in a directory of its own.
+pub fn is_book_entry(path: &str, books: &[String]) -> bool {
+    return Path::new(path).file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|fname| books.iter().any(|book| book == fname));
+}
+
+/// keeps only the groups of books, None if raw isn't utf8.
+/// Merging an upload of the slice against the slice as
+/// base leaves every other group of the store alone.
+pub fn slice_bytes(raw: &[u8], books: &[String]) -> Option<Vec<u8>> {
+    let mut kf = KeyFile::parse(str::from_utf8(raw).ok()?);
+    kf.groups.retain(|(group, _)| is_book_entry(group, books));
+    return Some(kf.serialize().into_bytes());
+}
+
+/// merge on raw file contents, None if any of them
+/// isn't utf8 and can't be a zathura key file.
+pub fn merge_bytes(base: &[u8], ours: &[u8], theirs: &[u8]) -> Option<Vec<u8>> {
+    let base = KeyFile::parse(str::from_utf8(base).ok()?);
+    let ours = KeyFile::parse(str::from_utf8(ours).ok()?);
+    let theirs = KeyFile::parse(str::from_utf8(theirs).ok()?);
+    return Some(merge(&base, &ours, &theirs).serialize().into_bytes());
+}
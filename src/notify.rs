@@ -0,0 +1,306 @@
+// ~~~~~~~ NOTIFY SOCKET PROTOCOL ~~~~~~~ //
+//
+// how zathura, or anything else opening books in
+// the disposable vm, talks to the client over the
+// unix socket at CLIENT_ZATH_SOCK_PATH. Any number
+// of callers can be connected at once.
+//
+// messages are framed as in framing.rs and encoded
+// like the qrexec messages in protocol.rs, a tag
+// byte followed by the fields of the variant.
+//
+// a connection starts with the caller sending
+// HELLO(u32 version) and the client answering with
+// its own HELLO, the client closes the connection
+// after that if the versions differ. Every command
+// after it gets exactly one reply, in order:
+//
+// caller command               client reply
+// ~~~~~~~~~~~~~~               ~~~~~~~~~~~~
+// OPENED(string book)       -> READY(string path)
+//                              | FAILED
+// CLOSED(string book)       -> OK
+// PAGE(string book, u32)    -> OK
+// BOOKMARK(string book,
+//     string id, u32 page)  -> OK
+// SYNC                      -> OK | FAILED
+//
+// book is a path relative to conf.book_dir, a bare
+// file name or a path below conf.book_dir. READY
+// carries the local path to open once the book has
+// been fetched. CLOSED, BOOKMARK and SYNC upload the
+// state zathura wrote so far, SYNC also fetches the
+// servers state. PAGE is only acknowledged, zathura
+// writes the page to its history when the book is
+// closed. FAILED is <code><string reason> like the
+// qrexec ERROR, a caller that sends something the
+// client can't decode gets FAILED and is dropped.
+//
+
+use crate::{
+    shared_consts::*,
+    error::{QzbError, ProtocolError, ErrorCode},
+    protocol::{Message, Encoder, Decoder},
+    framing::{frame, take_frame},
+};
+use std::{
+    io::{self, Read, Write, ErrorKind::*},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// bump this whenever the encoding of any
+/// message changes.
+pub const NOTIFY_VERSION: u32 = 1;
+/// nothing on this socket comes close, a book name
+/// is bounded by conf.limits.max_path_len.
+pub const NOTIFY_FRAME_LEN: u64 = 64 * 1024;
+
+const HELLO: u8 = b'h';
+const OPENED: u8 = b'o';
+const CLOSED: u8 = b'c';
+const PAGE: u8 = b'p';
+const BOOKMARK: u8 = b'b';
+const SYNC: u8 = b's';
+const READY: u8 = b'r';
+const OK: u8 = b'k';
+const FAILED: u8 = b'f';
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyMsg {
+    Hello(u32),
+    Opened(String),
+    Closed(String),
+    PageChanged { book: String, page: u32 },
+    BookmarkAdded { book: String, id: String, page: u32 },
+    SyncNow,
+    /// the local path of the book, see the top of the file
+    Ready(String),
+    Ok,
+    Failed { code: ErrorCode, reason: String },
+}
+
+impl NotifyMsg {
+    pub fn encode(&self) -> DRes<Vec<u8>> {
+        let mut enc = Encoder(vec!());
+        match self {
+            Self::Hello(version) => {
+                enc.tag(HELLO);
+                enc.u32(*version);
+            }
+            Self::Opened(book) => {
+                enc.tag(OPENED);
+                enc.bytes(book.as_bytes())?;
+            }
+            Self::Closed(book) => {
+                enc.tag(CLOSED);
+                enc.bytes(book.as_bytes())?;
+            }
+            Self::PageChanged { book, page } => {
+                enc.tag(PAGE);
+                enc.bytes(book.as_bytes())?;
+                enc.u32(*page);
+            }
+            Self::BookmarkAdded { book, id, page } => {
+                enc.tag(BOOKMARK);
+                enc.bytes(book.as_bytes())?;
+                enc.bytes(id.as_bytes())?;
+                enc.u32(*page);
+            }
+            Self::SyncNow => enc.tag(SYNC),
+            Self::Ready(path) => {
+                enc.tag(READY);
+                enc.bytes(path.as_bytes())?;
+            }
+            Self::Ok => enc.tag(OK),
+            Self::Failed { code, reason } => {
+                enc.tag(FAILED);
+                enc.tag(*code as u8);
+                enc.bytes(reason.as_bytes())?;
+            }
+        }
+
+        return Ok(enc.0);
+    }
+
+    pub fn decode(buf: &[u8]) -> DRes<Self> {
+        let mut dec = Decoder::new(buf);
+        let msg = match dec.u8()? {
+            HELLO => {
+                // a later version may add fields to its hello
+                let version = dec.u32()?;
+                if version != NOTIFY_VERSION {
+                    dec.skip_rest();
+                }
+                Self::Hello(version)
+            }
+            OPENED => Self::Opened(dec.string()?),
+            CLOSED => Self::Closed(dec.string()?),
+            PAGE => Self::PageChanged {
+                book: dec.string()?,
+                page: dec.u32()?,
+            },
+            BOOKMARK => Self::BookmarkAdded {
+                book: dec.string()?,
+                id: dec.string()?,
+                page: dec.u32()?,
+            },
+            SYNC => Self::SyncNow,
+            READY => Self::Ready(dec.string()?),
+            OK => Self::Ok,
+            FAILED => Self::Failed {
+                code: ErrorCode::from_u8(dec.u8()?)?,
+                reason: dec.string()?,
+            },
+            _ => Err(ProtocolError::UnknownMsg)?,
+        };
+
+        dec.finish()?;
+        return Ok(msg);
+    }
+
+    /// what the caller is told about e.
+    pub fn failed(e: &QzbError) -> Self {
+        let (code, reason) = match e.to_message() {
+            Message::Error { code, reason } => (code, reason),
+            _ => (ErrorCode::Internal, e.to_string()),
+        };
+
+        return Self::Failed { code, reason };
+    }
+}
+
+/// the caller end of the notify socket, blocking, used
+/// by qzb-open.
+pub struct NotifyClient {
+    stream: UnixStream,
+}
+
+impl NotifyClient {
+    /// connects and exchanges hellos, errors if the
+    /// client speaks another version.
+    pub fn connect(sock_path: impl AsRef<Path>, timeout: Duration) -> DRes<Self> {
+        let stream = UnixStream::connect(sock_path)?;
+        stream.set_read_timeout(Some(timeout))?;
+        let mut client = Self { stream };
+
+        match client.call(&NotifyMsg::Hello(NOTIFY_VERSION))? {
+            NotifyMsg::Hello(NOTIFY_VERSION) => (),
+            NotifyMsg::Hello(remote) => Err(ProtocolError::VersionMismatch {
+                local: NOTIFY_VERSION,
+                remote,
+            })?,
+            _ => Err(ProtocolError::Handshake)?,
+        }
+
+        return Ok(client);
+    }
+
+    /// sends msg and waits for its reply, a FAILED reply
+    /// is returned as QzbError::Remote.
+    pub fn call(&mut self, msg: &NotifyMsg) -> DRes<NotifyMsg> {
+        self.stream.write_all(&frame(&msg.encode()?)?)?;
+
+        let mut rbuf = vec!();
+        let mut buf = [0u8; 4096];
+        let payload = loop {
+            if let Some(payload) = take_frame(&mut rbuf, NOTIFY_FRAME_LEN)? {
+                break payload;
+            }
+
+            match self.stream.read(&mut buf) {
+                Ok(0) => Err(QzbError::PeerClosed)?,
+                Ok(nb) => rbuf.extend_from_slice(&buf[..nb]),
+                Err(e) if e.kind() == Interrupted => continue,
+                Err(e) => Err(e)?,
+            }
+        };
+
+        return match NotifyMsg::decode(&payload)? {
+            NotifyMsg::Failed { code, reason } => Err(QzbError::Remote { code, reason }),
+            reply => Ok(reply),
+        };
+    }
+
+    /// asks for book and returns the local path to
+    /// open once it's there.
+    pub fn open(&mut self, book: &str) -> DRes<PathBuf> {
+        return match self.call(&NotifyMsg::Opened(book.to_owned()))? {
+            NotifyMsg::Ready(path) => Ok(PathBuf::from(path)),
+            _ => Err(ProtocolError::UnexpectedMsg)?,
+        };
+    }
+}
+
+/// one caller on the notify socket, the socket is non
+/// blocking so frames are put together in rbuf.
+pub struct NotifyConn {
+    stream: UnixStream,
+    rbuf: Vec<u8>,
+    greeted: bool,
+}
+
+impl NotifyConn {
+    pub fn new(stream: UnixStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        return Ok(Self { stream, rbuf: vec!(), greeted: false });
+    }
+
+    /// reads whatever arrived and returns the complete
+    /// messages, None once the caller hung up. Anything
+    /// after the hello that doesn't decode is an error.
+    pub fn poll(&mut self) -> DRes<Option<Vec<NotifyMsg>>> {
+        let mut buf = [0u8; 4096];
+        let mut closed = false;
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(nb) => self.rbuf.extend_from_slice(&buf[..nb]),
+                Err(e) if e.kind() == WouldBlock => break,
+                Err(e) if e.kind() == Interrupted => continue,
+                Err(e) if e.kind() == ConnectionReset => {
+                    closed = true;
+                    break;
+                }
+                Err(e) => Err(e)?,
+            }
+        }
+
+        let mut msgs = vec!();
+        while let Some(payload) = take_frame(&mut self.rbuf, NOTIFY_FRAME_LEN)? {
+            msgs.push(NotifyMsg::decode(&payload)?);
+        }
+
+        if closed && msgs.is_empty() {
+            return Ok(None);
+        }
+        return Ok(Some(msgs));
+    }
+
+    /// answers a HELLO, returns false if the connection
+    /// has to be dropped because the versions differ.
+    /// Anything else before the HELLO is an error.
+    pub fn greet(&mut self, msg: &NotifyMsg) -> DRes<bool> {
+        let NotifyMsg::Hello(version) = msg else {
+            return Err(ProtocolError::Handshake.into());
+        };
+        self.send(&NotifyMsg::Hello(NOTIFY_VERSION))?;
+        self.greeted = *version == NOTIFY_VERSION;
+        return Ok(self.greeted);
+    }
+
+    pub fn is_greeted(&self) -> bool {
+        return self.greeted;
+    }
+
+    /// replies are small enough for the socket buffer, a
+    /// caller that doesn't read them gets dropped.
+    pub fn send(&mut self, msg: &NotifyMsg) -> DRes<()> {
+        self.stream.write_all(&frame(&msg.encode()?)?)?;
+        return Ok(());
+    }
+}
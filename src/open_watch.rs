@@ -0,0 +1,342 @@
+// fetches a book when any program opens its placeholder
+// in conf.book_dir, so stock zathura works without
+// writing to the notify socket. Opens are handed to the
+// session loop through the same fetch channel as the fuse
+// mount, see book_fs.rs.
+//
+// fanotify permission events hold the open until the book
+// is there but need CAP_SYS_ADMIN. Without it inotify
+// IN_OPEN is used, which only reports the open after the
+// fact, so the first open of a placeholder can still see
+// zeros and the book has to be opened again.
+
+use crate::{
+    shared_consts::*,
+    book_fs::Fetcher,
+    conf::Conf,
+    watcher::poll_readable,
+};
+use std::{
+    fs,
+    io::{self, ErrorKind::*},
+    collections::HashMap,
+    ffi::CString,
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{ffi::OsStrExt, fs::MetadataExt},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Weak, mpsc::{Receiver, TryRecvError}},
+    thread,
+    time::{Duration, Instant},
+};
+use inotify::{Inotify, WatchMask, WatchDescriptor, EventMask};
+use dbuggery::append;
+
+/// how often the fanotify thread checks on the fetches
+/// it holds opens for.
+const PERM_POLL: Duration = Duration::from_millis(50);
+/// upper bound on holding an open, the opener gets
+/// EPERM after that.
+const PERM_TIMEOUT: Duration = Duration::from_secs(60);
+/// inotify reports our own opens too, a path isn't
+/// fetched again within this long.
+const REFETCH_DELAY: Duration = Duration::from_secs(5);
+const EVENT_BUF_LEN: usize = 4096;
+
+const INOTIFY_MASK: WatchMask = WatchMask::OPEN
+    .union(WatchMask::CREATE)
+    .union(WatchMask::CLOSE_WRITE)
+    .union(WatchMask::ONLYDIR);
+
+pub enum OpenWatcher {
+    /// only the thread holds on to the group, see spawn_fanotify
+    Fanotify { fd: Weak<OwnedFd>, root: PathBuf },
+    Inotify,
+}
+
+impl OpenWatcher {
+    /// starts the watcher thread over conf.book_dir, every
+    /// open goes to fetcher.
+    pub fn spawn(conf: &Conf, fetcher: Fetcher) -> io::Result<Self> {
+        let root = PathBuf::from(&conf.book_dir);
+        match Self::spawn_fanotify(&root, fetcher.clone()) {
+            Ok(watcher) => return Ok(watcher),
+            Err(e) if e.kind() == PermissionDenied || e.raw_os_error() == Some(libc::ENOSYS) => {
+                append(
+                    format!("fanotify unavailable ({e}), watching book opens with inotify"),
+                    ERR_FNAME,
+                    ERR_LOG_DIR_NAME);
+            }
+            Err(e) => return Err(e),
+        }
+
+        return Self::spawn_inotify(&root, fetcher);
+    }
+
+    /// the group is closed as soon as the thread stops, the
+    /// kernel then lets every open it still held through
+    /// instead of leaving them blocked for good.
+    pub fn spawn_fanotify(root: &Path, fetcher: Fetcher) -> io::Result<Self> {
+        let flags = libc::FAN_CLASS_CONTENT | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK;
+        let event_flags = (libc::O_RDONLY | libc::O_LARGEFILE | libc::O_CLOEXEC) as libc::c_uint;
+        // SAFETY: plain syscall, the fd is owned right away
+        let raw = unsafe { libc::fanotify_init(flags, event_flags) };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: raw is a fresh fd nothing else owns
+        let fd = Arc::new(unsafe { OwnedFd::from_raw_fd(raw) });
+        let watcher = Self::Fanotify { fd: Arc::downgrade(&fd), root: root.to_owned() };
+        watcher.watch_tree()?;
+
+        let root = root.to_owned();
+        let _ = thread::spawn(move || {
+            if let Err(e) = fanotify_loop(&fd, &root, &fetcher) {
+                append(e.to_string(), ERR_FNAME, ERR_LOG_DIR_NAME);
+            }
+        });
+
+        return Ok(watcher);
+    }
+
+    pub fn spawn_inotify(root: &Path, fetcher: Fetcher) -> io::Result<Self> {
+        let mut inotify = InotifyOpens {
+            inotify: Inotify::init()?,
+            wds: HashMap::new(),
+            root: root.to_owned(),
+            creating: vec!(),
+            recent: HashMap::new(),
+        };
+        inotify.add_recursive(root)?;
+
+        let _ = thread::spawn(move || {
+            if let Err(e) = inotify.run(&fetcher) {
+                append(e.to_string(), ERR_FNAME, ERR_LOG_DIR_NAME);
+            }
+        });
+
+        return Ok(Self::Inotify);
+    }
+
+    /// marks every directory below book_dir, call after a
+    /// listing created new ones. The inotify thread adds
+    /// new directories on its own.
+    pub fn watch_tree(&self) -> io::Result<()> {
+        let Self::Fanotify { fd, root } = self else {
+            return Ok(());
+        };
+        // the thread is gone and logged why
+        let Some(fd) = fd.upgrade() else {
+            return Ok(());
+        };
+
+        return mark_recursive(fd.as_raw_fd(), root);
+    }
+}
+
+fn mark_recursive(fd: RawFd, dir: &Path) -> io::Result<()> {
+    let cpath = CString::new(dir.as_os_str().as_bytes())?;
+    let mask = libc::FAN_OPEN_PERM | libc::FAN_EVENT_ON_CHILD;
+    // SAFETY: cpath outlives the call
+    let ret = unsafe {
+        libc::fanotify_mark(fd, libc::FAN_MARK_ADD, mask, libc::AT_FDCWD, cpath.as_ptr())
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            mark_recursive(fd, &entry.path())?;
+        }
+    }
+
+    return Ok(());
+}
+
+/// an open held until its fetch is done
+struct HeldOpen {
+    fd: OwnedFd,
+    done: Receiver<Result<(), i32>>,
+    since: Instant,
+}
+
+/// answers our own opens right away, the fetch itself
+/// opens the book, and so is any open of a book that is
+/// already there. Every other open of a file below root
+/// is held until the session fetched it.
+fn fanotify_loop(fd: &OwnedFd, root: &Path, fetcher: &Fetcher) -> io::Result<()> {
+    const META_LEN: usize = mem::size_of::<libc::fanotify_event_metadata>();
+    let own_pid = std::process::id();
+    let mut buf = [0u8; EVENT_BUF_LEN];
+    let mut held: Vec<HeldOpen> = vec!();
+
+    loop {
+        if poll_readable(fd.as_raw_fd(), PERM_POLL)? {
+            // SAFETY: buf is valid for buf.len() bytes
+            let nb = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if nb < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != WouldBlock && e.kind() != Interrupted {
+                    return Err(e);
+                }
+            }
+
+            let mut events = &buf[..usize::try_from(nb).unwrap_or(0)];
+            while events.len() >= META_LEN {
+                // SAFETY: at least META_LEN bytes are left,
+                // read_unaligned copes with any alignment.
+                let meta: libc::fanotify_event_metadata =
+                    unsafe { events.as_ptr().cast::<libc::fanotify_event_metadata>().read_unaligned() };
+                if meta.vers != libc::FANOTIFY_METADATA_VERSION {
+                    return Err(io::Error::new(InvalidData, "unknown fanotify metadata version"));
+                }
+                let len = usize::try_from(meta.event_len).unwrap_or(usize::MAX).max(META_LEN);
+                events = events.get(len..).unwrap_or(&[]);
+
+                if meta.fd == libc::FAN_NOFD {
+                    continue;
+                }
+                // SAFETY: the kernel opened meta.fd for us
+                let event_fd = unsafe { OwnedFd::from_raw_fd(meta.fd) };
+                let fd_path = format!("/proc/self/fd/{}", meta.fd);
+                let rel = match u32::try_from(meta.pid) {
+                    Ok(pid) if pid == own_pid => None,
+                    _ if !is_placeholder(Path::new(&fd_path)) => None,
+                    _ => fs::read_link(&fd_path).ok()
+                        .and_then(|path| Some(path.strip_prefix(root).ok()?.to_owned())),
+                };
+
+                match rel.map(|rel| fetcher.request(&rel)) {
+                    Some(Ok(done)) => held.push(HeldOpen { fd: event_fd, done, since: Instant::now() }),
+                    Some(Err(_)) => respond(fd, &event_fd, libc::FAN_DENY)?,
+                    None => respond(fd, &event_fd, libc::FAN_ALLOW)?,
+                }
+            }
+        }
+
+        let mut still_held = vec!();
+        for open in held {
+            let response = match open.done.try_recv() {
+                Ok(Ok(())) => libc::FAN_ALLOW,
+                Ok(Err(_)) | Err(TryRecvError::Disconnected) => libc::FAN_DENY,
+                Err(TryRecvError::Empty) if open.since.elapsed() >= PERM_TIMEOUT => libc::FAN_DENY,
+                Err(TryRecvError::Empty) => {
+                    still_held.push(open);
+                    continue;
+                }
+            };
+            respond(fd, &open.fd, response)?;
+        }
+        held = still_held;
+    }
+}
+
+fn respond(fd: &OwnedFd, event_fd: &OwnedFd, response: u32) -> io::Result<()> {
+    let resp = libc::fanotify_response { fd: event_fd.as_raw_fd(), response };
+    let len = mem::size_of::<libc::fanotify_response>();
+    // SAFETY: resp outlives the call and len is its size
+    let nb = unsafe { libc::write(fd.as_raw_fd(), (&raw const resp).cast(), len) };
+    if nb < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    return Ok(());
+}
+
+/// the inotify fallback, only placeholders are fetched
+/// since our own opens show up here as well.
+struct InotifyOpens {
+    inotify: Inotify,
+    wds: HashMap<WatchDescriptor, PathBuf>,
+    root: PathBuf,
+    // files created but not yet closed, the client
+    // creating a placeholder opens it too.
+    creating: Vec<PathBuf>,
+    recent: HashMap<PathBuf, Instant>,
+}
+
+impl InotifyOpens {
+    fn run(&mut self, fetcher: &Fetcher) -> io::Result<()> {
+        let mut buf = [0u8; EVENT_BUF_LEN];
+        loop {
+            let mut new_dirs = vec!();
+            let mut opened = vec!();
+            let events = match self.inotify.read_events_blocking(&mut buf) {
+                Ok(events) => events,
+                Err(e) if e.kind() == Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events {
+                if event.mask.contains(EventMask::IGNORED) {
+                    let _ = self.wds.remove(&event.wd);
+                    continue;
+                }
+
+                let (Some(dir), Some(name)) = (self.wds.get(&event.wd), event.name) else {
+                    continue;
+                };
+                let path = dir.join(name);
+
+                if event.mask.contains(EventMask::ISDIR) {
+                    if event.mask.contains(EventMask::CREATE) {
+                        new_dirs.push(path);
+                    }
+                } else if event.mask.contains(EventMask::CREATE) {
+                    self.creating.push(path);
+                } else if event.mask.contains(EventMask::CLOSE_WRITE) {
+                    self.creating.retain(|x| *x != path);
+                } else if event.mask.contains(EventMask::OPEN) && !self.creating.contains(&path) {
+                    opened.push(path);
+                }
+            }
+
+            for dir in new_dirs {
+                self.add_recursive(&dir)?;
+            }
+
+            self.recent.retain(|_, at| at.elapsed() < REFETCH_DELAY);
+            for path in opened {
+                if self.recent.contains_key(&path) || !is_placeholder(&path) {
+                    continue;
+                }
+                let Ok(rel) = path.strip_prefix(&self.root) else { continue };
+
+                // nobody waits for the open, the session
+                // reports a failed fetch itself.
+                let _ = fetcher.request(rel);
+                let _ = self.recent.insert(path, Instant::now());
+            }
+        }
+    }
+
+    fn add_recursive(&mut self, dir: &Path) -> io::Result<()> {
+        let wd = match self.inotify.watches().add(dir, INOTIFY_MASK) {
+            Ok(wd) => wd,
+            Err(e) if e.kind() == NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let _ = self.wds.insert(wd, dir.to_owned());
+
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.add_recursive(&entry.path())?;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// placeholders are sparse, see client::BookNames, so
+/// they have no blocks on disk.
+pub fn is_placeholder(path: &Path) -> bool {
+    return fs::metadata(path)
+        .is_ok_and(|meta| meta.is_file() && (meta.len() == 0 || meta.blocks() == 0));
+}
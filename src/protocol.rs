@@ -0,0 +1,540 @@
+// ~~~~~~~ WIRE PROTOCOL ~~~~~~~ //
+//
+// every message is a single tag byte followed
+// by the fields of its variant, in order:
+//
+// u32       = 4 bytes little endian
+// u64, i64,
+// f64       = 8 bytes little endian
+// bool      = 1 byte, 0 or 1
+// bytes     = <u32 len><len bytes>
+// string    = bytes, must be utf8
+// path      = bytes, raw unix path, no encoding
+// hash      = 32 raw bytes, blake3
+// list<T>   = <u32 count><T><T>...
+// value     = <u8 kind><fields>, kind is one of
+//             0 null, 1 i64, 2 f64, 3 string, 4 bytes
+// table     = <string name><list<string> columns>
+//             <list<list<value>> rows>
+// code      = 1 byte, see error::ErrorCode
+// book      = <path><u64 size><i64 mtime><hash>
+//             <u8 doc type>, see book_index.rs
+//
+// a connection always starts with the client
+// sending HELLO and the server answering with
+// its own HELLO, both sides drop the connection
+// if the versions differ. HELLO is
+// <u32 version><string profile>, the profile picks
+// a state namespace on the server and is empty if
+// the client has none. Everything after the version
+// is skipped if it isn't ours, its encoding might
+// not be either.
+//
+// client request      server response
+// ~~~~~~~~~~~~~~      ~~~~~~~~~~~~~~~
+// GET_BOOKNAMES    -> BOOKNAMES(list<book>) | NO_CONTENT
+// GET_BOOK         -> BOOK
+// GET_SFILES       -> NUM_SFILES, SFILE * NUM_SFILES
+//                     | NO_CONTENT
+//
+// GET_SFILES carries a manifest of the (path, hash)
+// pairs the client already has, the server skips
+// every file whose hash matches.
+//
+// GET_DB           -> DB_TABLES
+//
+// SFILE            -> NO_CONTENT
+// NUM_SFILES, SFILE * NUM_SFILES
+//                  -> NO_CONTENT
+// RM_SFILE         -> NO_CONTENT
+// MV_SFILE         -> NO_CONTENT
+// DB_TABLES        -> NO_CONTENT
+//
+// any request can be answered with ERROR instead,
+// <code><string reason>, after which the session
+// goes on with the next request. That includes
+// anything the server can't decode or doesn't
+// expect as a request. A batch is always read to
+// its end, even if an earlier SFILE failed.
+//
+// the DB messages are only used with the sqlite
+// state backend, see db.rs.
+//
+// only the messages carrying a file, a listing or
+// tables may take frames up to Limits::max_frame_len,
+// see Message::max_frame_len. Every list is checked
+// against conf::Limits before any of it is decoded.
+//
+
+use crate::{
+    shared_consts::*,
+    error::{QzbError, ProtocolError, ErrorCode, MAX_REASON_LEN},
+    book_index::{BookEntry, DocType},
+    digest::{Hash, HASH_LEN},
+    db::{DbTable, DbValue, SYNCED_TABLES, MAX_DB_COLUMNS},
+    conf::Limits,
+};
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// bump this whenever the encoding of any
+/// message changes.
+pub const PROTOCOL_VERSION: u32 = 11;
+/// bounds HELLO frames, the profile name included.
+pub const HELLO_FRAME_LEN: u64 = 512;
+/// bounds ERROR frames, see error::MAX_REASON_LEN.
+pub const ERROR_FRAME_LEN: u64 = MAX_REASON_LEN as u64 + 16;
+
+const HELLO: u8 = b'h';
+const NO_CONTENT: u8 = b'n';
+const ERROR: u8 = b'e';
+const GET_BOOKNAMES: u8 = b'0';
+const BOOKNAMES: u8 = b'1';
+const GET_BOOK: u8 = b'2';
+const BOOK: u8 = b'3';
+const GET_SFILES: u8 = b'4';
+const NUM_SFILES: u8 = b'5';
+const SFILE: u8 = b'6';
+const RM_SFILE: u8 = b'7';
+const MV_SFILE: u8 = b'8';
+const GET_DB: u8 = b'9';
+const DB_TABLES: u8 = b'a';
+
+const VAL_NULL: u8 = 0;
+const VAL_INT: u8 = 1;
+const VAL_REAL: u8 = 2;
+const VAL_TEXT: u8 = 3;
+const VAL_BLOB: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Hello { version: u32, profile: Option<String> },
+    NoContent,
+    /// the request before it failed, see the top of the file
+    Error { code: ErrorCode, reason: String },
+    GetBookNames,
+    /// the servers book index, see book_index.rs
+    BookNames(Vec<BookEntry>),
+    /// a path relative to conf.book_dir or a bare file name
+    GetBook(String),
+    /// the path relative to conf.book_dir GET_BOOK resolved to
+    Book { path: String, contents: Vec<u8> },
+    /// manifest of the state files the client already has,
+    /// paths relative to conf.state_dir
+    GetStateFiles(Vec<(PathBuf, Hash)>),
+    NumStateFiles(u32),
+    /// path is relative to conf.state_dir
+    StateFile { path: PathBuf, is_dir: bool, contents: Vec<u8> },
+    /// removes a file or a whole directory below conf.state_dir
+    RemoveState(PathBuf),
+    /// both paths are relative to conf.state_dir
+    RenameState { from: PathBuf, to: PathBuf },
+    GetDbTables,
+    /// every synced table of the senders database
+    DbTables(Vec<DbTable>),
+}
+
+impl Message {
+    pub fn encode(&self) -> DRes<Vec<u8>> {
+        let mut enc = Encoder(vec!());
+        match self {
+            Self::Hello { version, profile } => {
+                enc.tag(HELLO);
+                enc.u32(*version);
+                enc.bytes(profile.as_deref().unwrap_or_default().as_bytes())?;
+            }
+            Self::NoContent => enc.tag(NO_CONTENT),
+            Self::Error { code, reason } => {
+                enc.tag(ERROR);
+                enc.tag(*code as u8);
+                enc.bytes(reason.as_bytes())?;
+            }
+            Self::GetBookNames => enc.tag(GET_BOOKNAMES),
+            Self::BookNames(bnames) => {
+                enc.tag(BOOKNAMES);
+                enc.len(bnames.len())?;
+                for book in bnames {
+                    enc.book(book)?;
+                }
+            }
+            Self::GetBook(bname) => {
+                enc.tag(GET_BOOK);
+                enc.bytes(bname.as_bytes())?;
+            }
+            Self::Book { path, contents } => {
+                enc.tag(BOOK);
+                enc.bytes(path.as_bytes())?;
+                enc.bytes(contents)?;
+            }
+            Self::GetStateFiles(manifest) => {
+                enc.tag(GET_SFILES);
+                enc.len(manifest.len())?;
+                for (path, hash) in manifest {
+                    enc.path(path)?;
+                    enc.0.extend_from_slice(hash);
+                }
+            }
+            Self::NumStateFiles(num) => {
+                enc.tag(NUM_SFILES);
+                enc.u32(*num);
+            }
+            Self::StateFile { path, is_dir, contents } => {
+                enc.tag(SFILE);
+                enc.path(path)?;
+                enc.bool(*is_dir);
+                enc.bytes(contents)?;
+            }
+            Self::RemoveState(path) => {
+                enc.tag(RM_SFILE);
+                enc.path(path)?;
+            }
+            Self::RenameState { from, to } => {
+                enc.tag(MV_SFILE);
+                enc.path(from)?;
+                enc.path(to)?;
+            }
+            Self::GetDbTables => enc.tag(GET_DB),
+            Self::DbTables(tables) => {
+                enc.tag(DB_TABLES);
+                enc.len(tables.len())?;
+                for table in tables {
+                    enc.table(table)?;
+                }
+            }
+        }
+
+        return Ok(enc.0);
+    }
+
+    /// the largest frame a message starting with tag
+    /// may take within limits.
+    pub fn max_frame_len(limits: &Limits, tag: u8) -> u64 {
+        return match tag {
+            HELLO => HELLO_FRAME_LEN,
+            ERROR => ERROR_FRAME_LEN,
+            BOOKNAMES | BOOK | GET_SFILES | SFILE | DB_TABLES => limits.max_frame_len(),
+            _ => limits.max_msg_len(),
+        };
+    }
+
+    pub fn decode(buf: &[u8], limits: &Limits) -> DRes<Self> {
+        let mut dec = Decoder::new(buf);
+        let msg = match dec.u8()? {
+            HELLO => match dec.u32()? {
+                PROTOCOL_VERSION => {
+                    let profile = dec.string()?;
+                    Self::Hello {
+                        version: PROTOCOL_VERSION,
+                        profile: (!profile.is_empty()).then_some(profile),
+                    }
+                }
+                version => {
+                    dec.skip_rest();
+                    Self::Hello { version, profile: None }
+                }
+            },
+            NO_CONTENT => Self::NoContent,
+            ERROR => Self::Error {
+                code: ErrorCode::from_u8(dec.u8()?)?,
+                reason: dec.string()?,
+            },
+            GET_BOOKNAMES => Self::GetBookNames,
+            BOOKNAMES => {
+                let num = dec.count(limits.max_files, "number of books")?;
+                let mut bnames = vec!();
+                for _ in 0..num {
+                    bnames.push(dec.book()?);
+                }
+                Self::BookNames(bnames)
+            }
+            GET_BOOK => Self::GetBook(dec.string()?),
+            BOOK => Self::Book {
+                path: dec.string()?,
+                contents: dec.bytes()?.to_vec(),
+            },
+            GET_SFILES => {
+                let num = dec.count(limits.max_files, "number of state files")?;
+                let mut manifest = vec!();
+                for _ in 0..num {
+                    manifest.push((dec.path()?, dec.hash()?));
+                }
+                Self::GetStateFiles(manifest)
+            }
+            NUM_SFILES => Self::NumStateFiles(dec.u32()?),
+            SFILE => Self::StateFile {
+                path: dec.path()?,
+                is_dir: dec.bool()?,
+                contents: dec.bytes()?.to_vec(),
+            },
+            RM_SFILE => Self::RemoveState(dec.path()?),
+            MV_SFILE => Self::RenameState {
+                from: dec.path()?,
+                to: dec.path()?,
+            },
+            GET_DB => Self::GetDbTables,
+            DB_TABLES => {
+                let num = dec.count(SYNCED_TABLES.len().try_into()?, "number of tables")?;
+                let mut tables = vec!();
+                let mut rows = 0;
+                for _ in 0..num {
+                    tables.push(dec.table(limits, &mut rows)?);
+                }
+                Self::DbTables(tables)
+            }
+            _ => Err(ProtocolError::UnknownMsg)?,
+        };
+
+        dec.finish()?;
+        return Ok(msg);
+    }
+
+    pub fn hello() -> Self {
+        return Self::Hello { version: PROTOCOL_VERSION, profile: None };
+    }
+
+    pub fn hello_as(profile: Option<&str>) -> Self {
+        return Self::Hello {
+            version: PROTOCOL_VERSION,
+            profile: profile.map(str::to_owned),
+        };
+    }
+
+    /// errors on anything that isn't a HELLO
+    /// carrying our own PROTOCOL_VERSION.
+    pub fn check_hello(&self) -> DRes<()> {
+        match self {
+            Self::Hello { version, .. } if *version == PROTOCOL_VERSION => Ok(()),
+            Self::Hello { version, .. } => Err(ProtocolError::VersionMismatch {
+                local: PROTOCOL_VERSION,
+                remote: *version,
+            })?,
+            _ => Err(ProtocolError::Handshake)?,
+        }
+    }
+}
+
+// also used by the notify socket, see notify.rs
+pub struct Encoder(pub Vec<u8>);
+impl Encoder {
+    pub fn tag(&mut self, tag: u8) {
+        self.0.push(tag);
+    }
+
+    pub fn u32(&mut self, num: u32) {
+        self.0.extend_from_slice(&num.to_le_bytes());
+    }
+
+    fn u64(&mut self, num: u64) {
+        self.0.extend_from_slice(&num.to_le_bytes());
+    }
+
+    fn i64(&mut self, num: i64) {
+        self.0.extend_from_slice(&num.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) -> DRes<()> {
+        self.u32(len.try_into()?);
+        return Ok(());
+    }
+
+    fn bool(&mut self, val: bool) {
+        self.0.push(val as u8);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> DRes<()> {
+        self.len(bytes.len())?;
+        self.0.extend_from_slice(bytes);
+        return Ok(());
+    }
+
+    fn path(&mut self, path: &Path) -> DRes<()> {
+        return self.bytes(path.as_os_str().as_bytes());
+    }
+
+    fn book(&mut self, book: &BookEntry) -> DRes<()> {
+        self.path(&book.path)?;
+        self.u64(book.size);
+        self.i64(book.mtime);
+        self.0.extend_from_slice(&book.hash);
+        self.tag(book.doc_type as u8);
+        return Ok(());
+    }
+
+    fn value(&mut self, val: &DbValue) -> DRes<()> {
+        match val {
+            DbValue::Null => self.tag(VAL_NULL),
+            DbValue::Integer(num) => {
+                self.tag(VAL_INT);
+                self.i64(*num);
+            }
+            DbValue::Real(num) => {
+                self.tag(VAL_REAL);
+                self.0.extend_from_slice(&num.to_le_bytes());
+            }
+            DbValue::Text(text) => {
+                self.tag(VAL_TEXT);
+                self.bytes(text.as_bytes())?;
+            }
+            DbValue::Blob(blob) => {
+                self.tag(VAL_BLOB);
+                self.bytes(blob)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn table(&mut self, table: &DbTable) -> DRes<()> {
+        self.bytes(table.name.as_bytes())?;
+        self.len(table.columns.len())?;
+        for col in &table.columns {
+            self.bytes(col.as_bytes())?;
+        }
+
+        self.len(table.rows.len())?;
+        for row in &table.rows {
+            self.len(row.len())?;
+            for val in row {
+                self.value(val)?;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    cursor: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        return Self { buf, cursor: 0 };
+    }
+
+    fn take(&mut self, len: usize) -> DRes<&'a [u8]> {
+        let end = self.cursor.checked_add(len)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or(ProtocolError::MsgFormat)?;
+        let slice = &self.buf[self.cursor..end];
+        self.cursor = end;
+        return Ok(slice);
+    }
+
+    pub fn u8(&mut self) -> DRes<u8> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    pub fn u32(&mut self) -> DRes<u32> {
+        return Ok(u32::from_le_bytes(self.take(4)?.try_into()?));
+    }
+
+    fn u64(&mut self) -> DRes<u64> {
+        return Ok(u64::from_le_bytes(self.take(8)?.try_into()?));
+    }
+
+    fn i64(&mut self) -> DRes<i64> {
+        return Ok(i64::from_le_bytes(self.take(8)?.try_into()?));
+    }
+
+    fn bool(&mut self) -> DRes<bool> {
+        return match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ProtocolError::MsgFormat)?,
+        };
+    }
+
+    /// the u32 count of a list, errors if it's above max.
+    fn count(&mut self, max: u64, what: &'static str) -> DRes<u32> {
+        let num = self.u32()?;
+        if u64::from(num) > max {
+            Err(QzbError::LimitExceeded { what, limit: max })?;
+        }
+        return Ok(num);
+    }
+
+    fn bytes(&mut self) -> DRes<&'a [u8]> {
+        let len = self.u32()?;
+        return self.take(len.try_into()?);
+    }
+
+    pub fn string(&mut self) -> DRes<String> {
+        return Ok(str::from_utf8(self.bytes()?)?.to_owned());
+    }
+
+    fn hash(&mut self) -> DRes<Hash> {
+        return Ok(self.take(HASH_LEN)?.try_into()?);
+    }
+
+    fn path(&mut self) -> DRes<PathBuf> {
+        return Ok(PathBuf::from(OsStr::from_bytes(self.bytes()?)));
+    }
+
+    fn book(&mut self) -> DRes<BookEntry> {
+        return Ok(BookEntry {
+            path: self.path()?,
+            size: self.u64()?,
+            mtime: self.i64()?,
+            hash: self.hash()?,
+            doc_type: DocType::from_u8(self.u8()?)?,
+        });
+    }
+
+    fn value(&mut self) -> DRes<DbValue> {
+        return Ok(match self.u8()? {
+            VAL_NULL => DbValue::Null,
+            VAL_INT => DbValue::Integer(self.i64()?),
+            VAL_REAL => DbValue::Real(f64::from_le_bytes(self.take(8)?.try_into()?)),
+            VAL_TEXT => DbValue::Text(self.string()?),
+            VAL_BLOB => DbValue::Blob(self.bytes()?.to_vec()),
+            _ => Err(ProtocolError::MsgFormat)?,
+        });
+    }
+
+    /// num_rows counts the rows of every table decoded so
+    /// far, together they stay within limits.max_db_rows.
+    fn table(&mut self, limits: &Limits, num_rows: &mut u64) -> DRes<DbTable> {
+        let name = self.string()?;
+        let mut columns = vec!();
+        for _ in 0..self.count(MAX_DB_COLUMNS, "number of columns")? {
+            columns.push(self.string()?);
+        }
+
+        let num = self.u32()?;
+        *num_rows = num_rows.saturating_add(num.into());
+        if *num_rows > limits.max_db_rows {
+            Err(QzbError::LimitExceeded { what: "number of rows", limit: limits.max_db_rows })?;
+        }
+
+        let mut rows = vec!();
+        for _ in 0..num {
+            if usize::try_from(self.u32()?)? != columns.len() {
+                Err(ProtocolError::DbTable(name.clone()))?;
+            }
+            let mut row = vec!();
+            for _ in 0..columns.len() {
+                row.push(self.value()?);
+            }
+            rows.push(row);
+        }
+
+        return Ok(DbTable { name, columns, rows });
+    }
+
+    /// for the rest of a message in an encoding we don't know.
+    pub fn skip_rest(&mut self) {
+        self.cursor = self.buf.len();
+    }
+
+    pub fn finish(&self) -> DRes<()> {
+        if self.cursor != self.buf.len() {
+            Err(ProtocolError::MsgFormat)?;
+        }
+        return Ok(());
+    }
+}
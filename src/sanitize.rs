@@ -0,0 +1,191 @@
+// every file name and path that arrives over qrexec
+// comes from the other side of a qubes trust boundary,
+// nothing from the peer is joined onto a local directory
+// without going through this module first. The same
+// goes for how much the peer gets to write, see
+// conf::Limits.
+
+use crate::{
+    shared_consts::*,
+    error::QzbError,
+    conf::Limits,
+};
+use std::{
+    fs,
+    io::ErrorKind::NotFound,
+    path::{Component, Path, PathBuf},
+};
+
+/// a single file name, i.e. a book name. Rejects
+/// anything that isn't exactly one normal component.
+pub fn safe_name(name: &str) -> DRes<&str> {
+    let reason = if name.is_empty() {
+        "empty file name"
+    } else if name.contains('/') {
+        "file name contains a path separator"
+    } else if name.contains('\0') {
+        "file name contains a nul byte"
+    } else if name == "." || name == ".." {
+        "file name refers to a directory"
+    } else {
+        return Ok(name);
+    };
+
+    return Err(reject(Path::new(name), reason));
+}
+
+/// a relative path below some local directory. Only
+/// normal components are allowed and the path has to be
+/// written the way Path::components would rebuild it,
+/// so "a//b", "a/./b" and a trailing slash are rejected.
+pub fn safe_rel_path(path: &Path) -> DRes<&Path> {
+    let raw = path.as_os_str().as_encoded_bytes();
+    if raw.is_empty() {
+        return Err(reject(path, "empty path"));
+    }
+    if raw.contains(&0) {
+        return Err(reject(path, "path contains a nul byte"));
+    }
+
+    let mut normalized = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => return Err(reject(path, "path contains ..")),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(reject(path, "path is absolute"));
+            }
+            Component::CurDir => return Err(reject(path, "path is not normalized")),
+        }
+    }
+
+    if normalized.as_os_str() != path.as_os_str() {
+        return Err(reject(path, "path is not normalized"));
+    }
+
+    return Ok(path);
+}
+
+/// joins rel onto root after safe_rel_path, every part of
+/// it that already exists must not be a symlink so that
+/// nothing below root can point the write elsewhere.
+pub fn resolve_in(root: &Path, rel: &Path) -> DRes<PathBuf> {
+    let rel = safe_rel_path(rel)?;
+    let mut fpath = root.to_owned();
+    for comp in rel.components() {
+        fpath.push(comp);
+        match fs::symlink_metadata(&fpath) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(reject(rel, "path goes through a symlink"));
+            }
+            Ok(_) => (),
+            // nothing below a missing component exists either
+            Err(e) if e.kind() == NotFound => break,
+            Err(e) => Err(e)?,
+        }
+    }
+
+    return Ok(root.join(rel));
+}
+
+/// resolve_in for a single file name, see safe_name.
+pub fn resolve_name(root: &Path, name: &str) -> DRes<PathBuf> {
+    return resolve_in(root, Path::new(safe_name(name)?));
+}
+
+/// rel against the path limits, see conf::Limits.
+pub fn check_path_limits(limits: &Limits, rel: &Path) -> DRes<()> {
+    let len: u64 = rel.as_os_str().len().try_into()?;
+    if len > limits.max_path_len {
+        Err(QzbError::LimitExceeded { what: "path length", limit: limits.max_path_len })?;
+    }
+
+    let depth: u64 = rel.components().count().try_into()?;
+    if depth > limits.max_path_depth {
+        Err(QzbError::LimitExceeded { what: "path depth", limit: limits.max_path_depth })?;
+    }
+
+    return Ok(());
+}
+
+/// a received file of len bytes against max_file_size.
+pub fn check_file_size(limits: &Limits, len: usize) -> DRes<()> {
+    let len: u64 = len.try_into()?;
+    if len > limits.max_file_size {
+        Err(QzbError::LimitExceeded { what: "file size", limit: limits.max_file_size })?;
+    }
+
+    return Ok(());
+}
+
+/// the entries below a state dir and the sum of their
+/// sizes, see check_state_write.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StateUsage {
+    files: u64,
+    bytes: u64,
+}
+
+/// errors unless writing len bytes to fpath keeps root within
+/// max_files and max_state_size, an existing fpath counts
+/// as replaced. root is only walked while usage is None,
+/// a write that passes is added to it. Whoever holds usage
+/// resets it once root may have changed some other way,
+/// i.e. after every request.
+pub fn check_state_write(
+    limits: &Limits,
+    usage: &mut Option<StateUsage>,
+    root: &Path,
+    fpath: &Path,
+    len: usize,
+) -> DRes<()> {
+    check_file_size(limits, len)?;
+    let mut next = match usage {
+        Some(usage) => *usage,
+        None => dir_usage(root)?,
+    };
+    match fs::symlink_metadata(fpath) {
+        Ok(meta) if meta.is_dir() => (),
+        Ok(meta) => next.bytes = next.bytes.saturating_sub(meta.len()),
+        Err(e) if e.kind() == NotFound => next.files += 1,
+        Err(e) => Err(e)?,
+    }
+    // and the parents the write creates along with it
+    for _ in fpath.ancestors().skip(1).take_while(|dir| *dir != root && !dir.exists()) {
+        next.files += 1;
+    }
+
+    if next.files > limits.max_files {
+        Err(QzbError::LimitExceeded { what: "number of state files", limit: limits.max_files })?;
+    }
+
+    next.bytes = next.bytes.saturating_add(len.try_into()?);
+    if next.bytes > limits.max_state_size {
+        Err(QzbError::LimitExceeded { what: "state size", limit: limits.max_state_size })?;
+    }
+
+    *usage = Some(next);
+    return Ok(());
+}
+
+fn dir_usage(dir: &Path) -> DRes<StateUsage> {
+    let mut usage = StateUsage::default();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        usage.files += 1;
+        if meta.is_dir() {
+            let sub = dir_usage(&entry.path())?;
+            usage.files += sub.files;
+            usage.bytes += sub.bytes;
+        } else {
+            usage.bytes += meta.len();
+        }
+    }
+
+    return Ok(usage);
+}
+
+fn reject(path: &Path, reason: &'static str) -> QzbError {
+    return QzbError::PathSafety { path: path.to_owned(), reason };
+}
@@ -1,186 +1,250 @@
-// PROGRESSION / CHECK LIST:  
-//
-// StateFiles: Decode and store server side,
-//  Decode and store client side,
-//  Requesting adaption (includes id) client side,
-//
-// BookNames:
-//  Request, client side
-//  storage, client side
-//
-// BookContent:
-//  Request, client side
-//  Storage, client side
-
 use crate::{
-    recv_seq,
     shared_consts::*,
     shared_fn::*,
-    conf::Conf,
+    error::{QzbError, ProtocolError},
+    protocol::Message,
+    framing::read_frame,
+    digest::{FileDigest, hash_bytes},
+    merge::{is_mergeable, merge_bytes, slice_bytes},
+    db::{self, DbTable, synced_as_file},
+    sanitize::{resolve_in, check_path_limits, StateUsage},
+    book_index::BookIndex,
+    conf::{Conf, Policy, Operation},
 };
 use std::{
     io,
+    env,
+    ffi::OsStr,
+    collections::HashMap,
     fs::{self, FileType},
     path::{PathBuf, Path},
-    num::TryFromIntError,
 };
 use qrexec_binds::{QrexecServer, QIO};
-use anyhow::anyhow;
 
 pub fn server_main(conf: Conf) -> DRes<()> {
-    let qrx = QrexecServer::new();
-    let mut qx = Qmunnicate::new(qrx);
+    // set by qrexec to the name of the calling vm
+    let Ok(remote_domain) = env::var("QREXEC_REMOTE_DOMAIN") else {
+        return Err(QzbError::Config("QREXEC_REMOTE_DOMAIN is not set".to_owned()));
+    };
+
+    return serve(QrexecServer::new(), &conf, &remote_domain);
+}
+
+/// runs a whole client session over qrx, returns once
+/// the client closes the pipe. Generic so the tests can
+/// drive it over an in-memory transport. remote_domain
+/// picks the policy and with the HELLO the state
+/// namespace the session runs under.
+pub fn serve<T: QIO>(qrx: T, conf: &Conf, remote_domain: &str) -> DRes<()> {
+    let mut qx = Qmunnicate::new(qrx, conf.policy_for(remote_domain).clone());
+    if let Some(cache) = &conf.index_cache {
+        qx.index = BookIndex::load(Path::new(cache), &conf.limits);
+    }
+    // everything below only ever sees its own namespace
+    let conf = &qx.handshake(conf, remote_domain)?;
+    while qx.server(conf)? {}
     return Ok(());
 }
 
 struct Qmunnicate<T: QIO> {
     qrx: T,
     buf: [u8; BLEN],
-    cursor: usize,
-    
-    // data: Extra is used to store protocol specific 
+
+    // data: Extra is used to store protocol specific
     // data which needs to persist between send
     // and recv calls to properly carry out the
     // Recv.*::handle function i.e. Book needs to
     // remember the book name.
     data: Extra,
-}
 
-impl<T: QIO> Qmunnicate<T> {
-    /// First available byte index in Responder.buf not 
-    /// used by the header.
-    const CONT_IDX: usize = 4;
-    
-    fn new(qrx: T) -> Self {
-        Self { qrx, buf: [0u8; BLEN], cursor: 0, data: None }
-    }
+    // the contents of every mergeable state file as
+    // this client last saw them, the base of the
+    // three-way merge when it uploads them again.
+    bases: HashMap<PathBuf, Vec<u8>>,
 
-    /// returns the number of reads, sets buf header
-    /// with the number of reads required for a response
-    /// of msg_len length. Sets the cursor to point to 
-    /// next available byte.
-    fn set_numreads(
-        &mut self,
-        msg_len: usize,
-    ) -> Result<u32, TryFromIntError> {
-        let (nrb, nr) = num_reads_encode(msg_len)?;
-        self.cursor += set_slice(&mut self.buf[self.cursor..], &nrb); 
-        return Ok(nr);
-    }
+    // same as bases for the sqlite backend, the
+    // tables this client last saw.
+    db_base: Vec<DbTable>,
+
+    // every book this client requested, with
+    // conf.slice_state only their state is sent.
+    books: Vec<String>,
+
+    // what the calling vm is allowed to do.
+    policy: Policy,
+
+    // the books below conf.book_dir, rebuilt by every
+    // listing and whenever a requested book is missing.
+    // Starts out as conf.index_cache.
+    index: BookIndex,
 
-    /// initially the cursor of the Responder object is 
-    /// set to the number of bytes from the first read.
-    fn server(&mut self, conf: &Conf) -> DRes<()> {
-        self.cursor = self.qrx.read(&mut self.buf)?;
-        self.qrx.write(RECV_SEQ)?;
-        match self.buf[0] {
-            //VAR_SEND_SFILE => recv_file(qrx, conf, rbuf, nb)?,
-            StateFiles::ID => StateFiles::send(self, &conf, None)?,
+    // what the state dir holds as of the last upload of
+    // the current request, see sanitize::check_state_write.
+    usage: Option<StateUsage>,
 
-            Book::ID => Book::send(self, &conf, None)?,
-            BookNames::ID => BookNames::send(self, &conf, None)?,
+    // set once a frame inside a batch couldn't be read,
+    // the rest of it would be taken for new requests.
+    broken: bool,
+}
 
-            _ => unreachable!(),
+impl<T: QIO> Qmunnicate<T> {
+    fn new(qrx: T, policy: Policy) -> Self {
+        Self {
+            qrx,
+            buf: [0u8; BLEN],
+            data: Extra::None,
+            bases: HashMap::new(),
+            db_base: vec!(),
+            books: vec!(),
+            policy,
+            index: BookIndex::default(),
+            usage: None,
+            broken: false,
+        }
+    }
+
+    fn permit(&self, op: Operation) -> DRes<()> {
+        if !self.policy.allows(op) {
+            Err(QzbError::Denied(op))?;
         }
 
         return Ok(());
-    }  
-}
+    }
 
-fn inner_recv<T: QIO>(qc: &mut Qmunnicate<T>) -> DRes<Vec<u8>> {
-    let mut content: Vec<u8> = vec!();
-    qc.cursor = 0;
+    /// the client always speaks first, its HELLO is
+    /// answered even if the versions differ so that the
+    /// client can report the mismatch as well. Returns
+    /// conf with the state dir of the clients namespace,
+    /// if that can't be set up it gets an ERROR instead.
+    fn handshake(&mut self, conf: &Conf, remote_domain: &str) -> DRes<Conf> {
+        let hello = recv_hello(&mut self.qrx, &mut self.buf)?;
+        if let Err(e) = hello.check_hello() {
+            send_msg(&mut self.qrx, &Message::hello())?;
+            return Err(e);
+        }
 
-    qc.cursor = qc.qrx.read(&mut qc.buf)?;
-    qc.qrx.write(RECV_SEQ)?;
+        let Message::Hello { profile, .. } = hello else {
+            return Err(QzbError::Internal("check_hello passed something else"));
+        };
+        let state_dir = match conf.namespace_for(remote_domain, profile.as_deref()) {
+            Ok(state_dir) => state_dir,
+            Err(e) => {
+                send_msg(&mut self.qrx, &e.to_message())?;
+                return Err(e);
+            }
+        };
 
-    let mut num_reads = num_reads_decode(
-        qc.buf[..NUM_READS_LEN].try_into()?);
-    num_reads -= 1;
+        send_msg(&mut self.qrx, &Message::hello())?;
+        return Ok(Conf { state_dir, ..conf.clone() });
+    }
 
-    content.extend_from_slice(&qc.buf[NUM_READS_LEN..qc.cursor]);
-    qc.qrx.write(RECV_SEQ)?;
+    /// reads one request and dispatches it to the
+    /// matching Send / RecvOne / RecvMore implementation,
+    /// returns false once the client has closed the pipe.
+    /// Requests that fail are answered with ERROR and
+    /// the session goes on, see protocol.rs. A frame that
+    /// can't be read ends it, inside a batch as well.
+    fn server(&mut self, conf: &Conf) -> DRes<bool> {
+        let max_len = |tag| Message::max_frame_len(&conf.limits, tag);
+        let Some(frame) = read_frame(&mut self.qrx, &mut self.buf, max_len)? else {
+            return Ok(false);
+        };
 
-    while num_reads != 0 {
-        qc.cursor = qc.qrx.read(&mut qc.buf)?;  
-        qc.qrx.write(RECV_SEQ)?;
+        let res = Message::decode(&frame, &conf.limits)
+            .and_then(|request| self.dispatch(conf, request));
+        self.data = Extra::None;
+        self.usage = None;
+        match res {
+            Ok(()) => (),
+            Err(e @ QzbError::PeerClosed) => return Err(e),
+            Err(e) if self.broken => return Err(e),
+            Err(e) => send_msg(&mut self.qrx, &e.to_message())?,
+        }
 
-        content.extend_from_slice(&qc.buf[..qc.cursor]);
-        num_reads -= 1;
+        return Ok(true);
     }
 
-    return Ok(content);
-}
+    fn dispatch(&mut self, conf: &Conf, request: Message) -> DRes<()> {
+        match request {
+            Message::GetStateFiles(manifest) => {
+                self.permit(Operation::FetchState)?;
+                self.data = Extra::Manifest(manifest.into_iter().collect());
+                StateFiles::send(self, conf)?;
+            }
+            Message::NumStateFiles(num) => {
+                StateFiles::recv_more(self, conf, num)?;
+                send_msg(&mut self.qrx, &Message::NoContent)?;
+            }
+            Message::StateFile { .. }
+            | Message::RemoveState(_)
+            | Message::RenameState { .. } => {
+                StateFiles::handle(self, conf, request)?;
+                send_msg(&mut self.qrx, &Message::NoContent)?;
+            }
+            Message::GetBook(bname) => {
+                self.permit(Operation::FetchBooks)?;
+                self.data = Extra::FileName(bname);
+                Book::send(self, conf)?;
+            }
+            Message::GetBookNames => {
+                self.permit(Operation::ListBooks)?;
+                BookNames::send(self, conf)?;
+            }
+            Message::GetDbTables => {
+                self.permit(Operation::FetchState)?;
+                DbTables::send(self, conf)?;
+            }
+            Message::DbTables(_) => {
+                DbTables::handle(self, conf, request)?;
+                send_msg(&mut self.qrx, &Message::NoContent)?;
+            }
+            _ => Err(ProtocolError::UnexpectedMsg)?,
+        }
 
-trait RecvOne<T: QIO> {
-    fn recv(qc: &mut Qmunnicate<T>, conf: &Conf, bname: Option<&str>) -> DRes<()> {
-        let content = inner_recv(qc)?;
-        Self::handle(conf, content)?;
         return Ok(());
     }
+}
 
-    fn handle(conf: &Conf, cont: Vec<u8>) -> DRes<()>; 
+trait RecvOne<T: QIO> {
+    fn handle(qc: &mut Qmunnicate<T>, conf: &Conf, msg: Message) -> DRes<()>;
 }
 
-trait RecvMore<T: QIO> {
-    fn recv_more<const ITERS: usize>(
-        qc: &mut Qmunnicate<T>, conf: &Conf,
-    ) -> DRes<()> { 
-        let mut content = vec!();
-        for _ in 0..ITERS {
-            content.push(inner_recv(qc)?); 
+trait RecvMore<T: QIO>: RecvOne<T> {
+    /// receives the num messages announced by the
+    /// request, each one goes through RecvOne::handle.
+    /// The whole batch is read even if handling one of
+    /// them fails, the first error is returned after.
+    /// Failing to read a frame stops right away and marks
+    /// the session broken, see Qmunnicate::server.
+    fn recv_more(qc: &mut Qmunnicate<T>, conf: &Conf, num: u32) -> DRes<()> {
+        let mut res = Ok(());
+        for _ in 0..num {
+            let max_len = |tag| Message::max_frame_len(&conf.limits, tag);
+            let frame = match read_frame(&mut qc.qrx, &mut qc.buf, max_len) {
+                Ok(Some(frame)) => frame,
+                res => {
+                    qc.broken = true;
+                    return Err(res.err().unwrap_or(QzbError::PeerClosed));
+                }
+            };
+            let handled = Message::decode(&frame, &conf.limits)
+                .and_then(|msg| Self::handle(qc, conf, msg));
+            if res.is_ok() {
+                res = handled;
+            }
         }
 
-        Self::handle(conf, content)?;
-        return Ok(());
+        return res;
     }
-
-    fn handle(conf: &Conf, cont: Vec<Vec<u8>>) -> DRes<()>;
 }
 
 trait Send<T: QIO> {
-    fn send(qc: &mut Qmunnicate<T>, conf: &Conf, identifier: Option<u8>) -> DRes<()> {
+    fn send(qc: &mut Qmunnicate<T>, conf: &Conf) -> DRes<()> {
         let cont = Self::contents(conf, qc)?;
-        if let Some(identifier) = identifier {
-            qc.buf[0] = identifier;
-            qc.cursor += 1;
-        }
-
         match cont {
-            Content::One(cont) => Self::send_one(qc, cont)?,
-            Content::More(cont) => Self::send_more(qc, cont)?,
-            // None on the server side is indicated by 0 as num_reads
-            Content::None => {
-                qc.qrx.write(&[0,0,0,0,1])?;
-                recv_seq!(qc.qrx, &mut qc.buf); 
-            },
-            // None on the client side can be completely ignored
-            Content::None if identifier.is_some() => (),
-        }
-
-        qc.cursor = 0;
-        return Ok(());
-    }
-
-    fn send_one(qc: &mut Qmunnicate<T>, cont: Vec<u8>) -> DRes<()> {
-        let mut num_reads = qc.set_numreads(cont.len())?;
-        let mut max_cursor;
-        while num_reads != 0 {
-            if num_reads == 1 {
-                max_cursor = cont.len();
-            } else {
-                max_cursor = BLEN;
-            }
-
-            qc.cursor += set_slice(
-                &mut qc.buf[qc.cursor..],
-                &cont[..max_cursor]);
-
-            qc.qrx.write(&qc.buf[..qc.cursor])?;
-            recv_seq!(qc.qrx, &mut qc.buf);
-
-            qc.cursor = 0;
-            num_reads -= 1;
+            Content::One(msg) => send_msg(&mut qc.qrx, &msg)?,
+            Content::More(msgs) => Self::send_more(qc, msgs)?,
+            Content::None => send_msg(&mut qc.qrx, &Message::NoContent)?,
         }
 
         return Ok(());
@@ -188,10 +252,10 @@ trait Send<T: QIO> {
 
     fn send_more(
         qc: &mut Qmunnicate<T>,
-        conts: Vec<Vec<u8>>,
+        msgs: Vec<Message>,
     ) -> DRes<()> {
-        for cont in conts {      
-            Self::send_one(qc, cont)?;
+        for msg in msgs {
+            send_msg(&mut qc.qrx, &msg)?;
         }
 
         return Ok(());
@@ -204,104 +268,61 @@ trait Send<T: QIO> {
 }
 
 struct BookNames;
-impl BookNames {
-    const ID: u8 = b'0';
-}
-
 impl<T: QIO> Send<T> for BookNames {
-    /// NO SEMICOLONS IN THE BOOKNAMES, who puts a semicolon in a title anyway
-    fn contents(conf: &Conf, _: &mut Qmunnicate<T>) -> DRes<Content> {
-        let mut bnames: Vec<u8> = vec!();
-        let bdir_entries = fs::read_dir(&conf.book_dir)?;
-        for bentry in bdir_entries {
-            bnames.extend_from_slice(
-                bentry?.file_name()
-                    .to_str() 
-                    .ok_or(anyhow!(INVALID_ENC_ERR))?
-                    .as_bytes());
-            bnames.push(b';');
-        }
-
-        if bnames.is_empty() {
-            bnames.push(NONE);
+    /// the index of every book below book dir, the
+    /// listing always rebuilds it.
+    fn contents(conf: &Conf, qc: &mut Qmunnicate<T>) -> DRes<Content> {
+        qc.index.rescan(conf)?;
+        if qc.index.entries.is_empty() {
+            return Ok(Content::None);
         }
 
-        return Ok(Content::One(bnames));
-    }
-}
-
-impl<T: QIO> RecvOne<T> for BookNames {
-    fn handle(conf: &Conf, cont: Vec<u8>) -> DRes<()> {
-        let mut path = PathBuf::from(&conf.book_dir);
-
-        for bname in str::from_utf8(&cont)?.split(';') {
-            path.push(bname);
-            fs::File::create(&path)?;      
-            let _ = path.pop();
-        }; 
-
-        return Ok(());
+        return Ok(Content::One(Message::BookNames(qc.index.entries.clone())));
     }
 }
 
 struct Book;
-impl Book {           
-    const ID: u8 = b'2';
-
-    fn find_book(
-        book_dir: &Path,
-        bname: &str,
-    ) -> io::Result<Option<PathBuf>> {
-        let path = book_dir.join(bname).to_owned();
-        if fs::exists(&path)? {
-            return Ok(Some(path));
-        } else {
-            for file in fs::read_dir(book_dir)? {
-                let file = file?;
-                if file.file_type()?.is_dir() {
-                    return Self::find_book(&file.path(), bname);
-                } 
-            }
-            return Ok(None);
-        }                
-    }
-}
-
-impl<T: QIO> RecvOne<T> for Book {
-    fn handle(qc: &mut Qmunnicate<T>, conf: &Conf, cont: Vec<u8>) -> DRes<()> {
-        let bname = match qc.data {
-            Extra::FileName(bname) => bname,
-            Extra::None => return Ok(()),
-        };
-
-        fs::write(
-            Path::from(format!("{}/{}", conf.book_dir, bname)), cont)?;
-    }
-}
-
 impl<T: QIO> Send<T> for Book {
-    /// returns the book cont if it exists in book dir, else
-    /// returns an empty vector if it doesn't exist.
+    /// returns the book contents if it exists in book dir,
+    /// else QzbError::BookUnavailable. The name is a path
+    /// relative to book dir or a bare file name, see
+    /// BookIndex::find.
     fn contents(
         conf: &Conf,
         qc: &mut Qmunnicate<T>,
     ) -> DRes<Content> {
-        let bname = str::from_utf8(&qc.buf[1..qc.cursor])?;
-        let bpath = Self::find_book(Path::new(&conf.book_dir), bname)?;
-        if let Some(bpath) = bpath { 
-            return Ok(Content::One(fs::read(&bpath)?));
-            qc.data = bname.to_owned();
-        } else {
-            return Ok(Content::None);
+        let bname = match &qc.data {
+            Extra::FileName(bname) => bname,
+            _ => Err(QzbError::Internal("Book::contents without a book name"))?,
+        };
+        check_path_limits(&conf.limits, Path::new(bname))?;
+
+        // the book might have been added since the index was built
+        let entry = match qc.index.find(bname) {
+            Err(QzbError::BookUnavailable) => {
+                qc.index.rescan(conf)?;
+                qc.index.find(bname)?
+            }
+            res => res?,
+        };
+
+        // state files only know books by their basename
+        if let Some(fname) = entry.path.file_name().and_then(OsStr::to_str)
+            && !qc.books.iter().any(|book| book == fname) {
+            qc.books.push(fname.to_owned());
         }
+
+        let contents = fs::read(Path::new(&conf.book_dir).join(&entry.path))?;
+        let path = entry.path.to_str()
+            .ok_or(ProtocolError::InvalidEnc)?
+            .to_owned();
+        return Ok(Content::One(Message::Book { path, contents }));
     }
-} 
+}
 
 
 struct StateFiles;
 impl StateFiles {
-    const ID: u8 = b'3'; 
-
     fn recurse_files(
         read_dir: fs::ReadDir,
         files: &mut Vec<(PathBuf, FileType)>,
@@ -311,15 +332,19 @@ impl StateFiles {
             let path = file.path();
             let file_type = file.file_type()?;
 
-            if file_type.is_file() {
+            if is_partial(&path) {
+                continue;
+            } else if file_type.is_file() {
                 files.push((path, file_type));
             } else if file_type.is_dir() {
+                // parents go first so the receiver can
+                // create them before their children.
+                files.push((path.clone(), file_type));
                 Self::recurse_files(
                     fs::read_dir(&path)?,
                     files)?;
-                files.push((path, file_type));
             } else if file_type.is_symlink() {
-                Err(anyhow!(SYMLINK_ERR))?;
+                Err(QzbError::PathSafety { path, reason: "symlinks are not synced" })?;
             }
         }
 
@@ -328,41 +353,176 @@ impl StateFiles {
 }
 
 impl<T: QIO> Send<T> for StateFiles {
-    /// directories are denoted by zero contents, ie. the accompanying 
-    /// vector of like ordered names will not have a content indice set
-    /// Paths and Contents are sent in this order (the paths sent are 
-    /// stripped of the prefix conf.state_dir): 
-    /// * 1: Paths, 
-    /// * 2: Contents,
-    fn contents(conf: &Conf, _: &mut Qmunnicate<T>) -> DRes<Content> {
+    /// directories are sent with empty contents, the paths
+    /// sent are stripped of the prefix conf.state_dir.
+    /// NUM_SFILES goes first so the client knows how many
+    /// SFILE messages follow. Anything the clients manifest
+    /// already lists with the same hash is left out.
+    /// With conf.slice_state mergeable files only carry the
+    /// groups of the books requested so far and nothing else
+    /// is sent, there is no telling which book it is about.
+    fn contents(conf: &Conf, qc: &mut Qmunnicate<T>) -> DRes<Content> {
         let mut file_paths: Vec<(PathBuf, FileType)> = vec!();
-        let mut contents: Vec<u8> = vec!();
-        let mut fpaths: Vec<u8> = vec!();
+        let mut msgs = vec!();
+        let manifest = match &qc.data {
+            Extra::Manifest(manifest) => manifest,
+            _ => &HashMap::new(),
+        };
 
         Self::recurse_files(
             fs::read_dir(&conf.state_dir)?,
             &mut file_paths)?;
 
-        if file_paths.is_empty() {
+        for (path, ftype) in file_paths {
+            let Ok(rel_path) = path.strip_prefix(&conf.state_dir) else {
+                return Err(QzbError::Internal("recurse_files left the state dir"));
+            };
+            let rel_path = rel_path.to_owned();
+            if !synced_as_file(conf, &rel_path) {
+                continue;
+            }
+
+            let mut contents = if ftype.is_dir() {
+                vec!()
+            } else {
+                fs::read(&path)?
+            };
+
+            let mergeable = is_mergeable(&rel_path) && !ftype.is_dir();
+            if conf.slice_state {
+                // anything but a key file could be about
+                // any book, there is nothing to slice it by
+                let slice = match mergeable {
+                    true => slice_bytes(&contents, &qc.books),
+                    false => None,
+                };
+                let Some(slice) = slice else {
+                    continue;
+                };
+                contents = slice;
+            }
+
+            let hash = if ftype.is_dir() {
+                FileDigest::dir().hash
+            } else {
+                hash_bytes(&contents)
+            };
+
+            if mergeable {
+                let _ = qc.bases.insert(rel_path.clone(), contents.clone());
+            }
+
+            if manifest.get(&rel_path) == Some(&hash) {
+                continue;
+            }
+
+            msgs.push(Message::StateFile {
+                path: rel_path,
+                is_dir: ftype.is_dir(),
+                contents,
+            });
+        }
+
+        if msgs.is_empty() {
             return Ok(Content::None);
         }
 
-        for (path, ftype) in file_paths {
-            let fpath_bytes = path
-                .as_path()
-                .strip_prefix(&conf.state_dir)?
-                .as_os_str()
-                .as_encoded_bytes();
-
-            if ftype.is_dir() {
-                fpaths.extend_from_slice(fpath_bytes);
-            } else if ftype.is_file() {
-                fpaths.extend_from_slice(fpath_bytes);
-                let fcont_bytes = fs::read(path)?;
-                contents.extend_from_slice(&fcont_bytes);
+        msgs.insert(0, Message::NumStateFiles(msgs.len().try_into()?));
+        return Ok(Content::More(msgs));
+    }
+}
+
+impl StateFiles {
+    /// merges an uploaded zathura key file into the stored
+    /// one, the upload becomes the base for the next merge
+    /// since that is what the client has now. An upload
+    /// that can't be merged is refused.
+    fn merge_upload<T: QIO>(
+        qc: &mut Qmunnicate<T>,
+        conf: &Conf,
+        path: PathBuf,
+        theirs: Vec<u8>,
+    ) -> DRes<()> {
+        // another session of the namespace could write
+        // it between the read and the write below
+        let _lock = lock_state_dir(conf)?;
+        let ours = match fs::read(resolve_in(Path::new(&conf.state_dir), &path)?) {
+            Ok(ours) => ours,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec!(),
+            Err(e) => Err(e)?,
+        };
+
+        let base = qc.bases.get(&path).map(Vec::as_slice).unwrap_or_default();
+        // taking theirs as is would replace the whole
+        // store with what may only be a slice of it.
+        let contents = merge_bytes(base, &ours, &theirs)
+            .ok_or(ProtocolError::InvalidEnc)?;
+        let _ = qc.bases.insert(path.clone(), theirs);
+
+        return write_state_file(
+            conf, &mut qc.usage, Message::StateFile { path, is_dir: false, contents });
+    }
+}
+
+impl<T: QIO> RecvMore<T> for StateFiles {}
+
+impl<T: QIO> RecvOne<T> for StateFiles {
+    /// every upload, batched or not, ends up here so
+    /// this is where the policy is checked for them.
+    fn handle(qc: &mut Qmunnicate<T>, conf: &Conf, msg: Message) -> DRes<()> {
+        qc.permit(Operation::UploadState)?;
+        let paths = match &msg {
+            Message::StateFile { path, .. } | Message::RemoveState(path) => vec!(path),
+            Message::RenameState { from, to } => vec!(from, to),
+            _ => vec!(),
+        };
+        // the sqlite backend owns the live database, see db.rs
+        if let Some(path) = paths.into_iter().find(|path| !synced_as_file(conf, path)) {
+            Err(QzbError::PathSafety {
+                path: path.to_owned(),
+                reason: "the database is only synced as tables",
+            })?;
+        }
+
+        return match msg {
+            Message::StateFile { path, is_dir: false, contents } if is_mergeable(&path) => {
+                Self::merge_upload(qc, conf, path, contents)
+            }
+            Message::RemoveState(path) => {
+                let _ = qc.bases.remove(&path);
+                qc.usage = None;
+                remove_state_path(conf, &path)
             }
+            Message::RenameState { from, to } => rename_state_path(conf, &from, &to),
+            msg => write_state_file(conf, &mut qc.usage, msg),
+        };
+    }
+}
+
+struct DbTables;
+impl<T: QIO> Send<T> for DbTables {
+    fn contents(conf: &Conf, qc: &mut Qmunnicate<T>) -> DRes<Content> {
+        let conn = db::open(&db::db_path(conf))?;
+        qc.db_base = db::read_tables(&conn)?;
+        if conf.slice_state {
+            db::slice_tables(&mut qc.db_base, &qc.books);
         }
+        return Ok(Content::One(Message::DbTables(qc.db_base.clone())));
+    }
+}
 
-        return Ok(Content::More(vec![fpaths, contents]));
-    } 
+impl<T: QIO> RecvOne<T> for DbTables {
+    /// the upload becomes the base for the next one,
+    /// like StateFiles::merge_upload.
+    fn handle(qc: &mut Qmunnicate<T>, conf: &Conf, msg: Message) -> DRes<()> {
+        qc.permit(Operation::UploadState)?;
+        let Message::DbTables(theirs) = msg else {
+            return Err(ProtocolError::UnexpectedMsg.into());
+        };
+
+        let mut conn = db::open(&db::db_path(conf))?;
+        db::apply_tables(&mut conn, &qc.db_base, &theirs, conf.limits.max_state_size)?;
+        qc.db_base = theirs;
+        return Ok(());
+    }
 }
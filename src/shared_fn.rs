@@ -1,145 +1,186 @@
-use crate::shared_consts::*;
-use std::num::TryFromIntError;
+use crate::{
+    shared_consts::*,
+    error::{QzbError, ProtocolError},
+    protocol::{Message, ERROR_FRAME_LEN},
+    framing::{read_frame, write_frame},
+    digest::Hash,
+    db::DbTable,
+    sanitize::{resolve_in, check_path_limits, check_state_write, StateUsage},
+    conf::{Conf, Limits},
+};
+use std::{
+    fs,
+    process,
+    io::ErrorKind::NotFound,
+    collections::HashMap,
+    ffi::OsString,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use qrexec_binds::QIO;
 
 pub enum Content {
-    One(Vec<u8>),
-    More(Vec<Vec<u8>>),
+    One(Message),
+    More(Vec<Message>),
     None,
 }
 
 pub enum Extra {
     FileName(String),
+    Manifest(HashMap<PathBuf, Hash>),
+    Paths(Vec<PathBuf>),
+    Tables(Vec<DbTable>),
     None,
 }
 
-/// takes a vector of AsRef<[u8]>, compacts them into a vector
-/// in the following format based on data initial vectors indice
-/// boundaries. cursor is an offset to correct the ordered_indices
-/// based on the context of the buffer in which it is placed, it's 
-/// the starting index where the indexed_data will be placed:
-///
-/// 1 =  num_indexes: u32
-/// 2 =  index pairs: (u32, u32): length = 1 (per index, not per pair)
-/// 3 =  data: byte stream
-///
-/// the byte stream can be reassembled into multiple distinct byte
-/// sets as seperated by indices
-pub fn index_data(
-    data: Vec<impl AsRef<[u8]>>,
-    mut cursor: usize,
-) -> Result<Vec<u8>, TryFromIntError> { 
-    let mut compacted: Vec<u8> = vec!();
-    let mut ordered_indices: Vec<u8> = vec!();
-
-    for part in data {
-        let len = part.as_ref().len();
-
-        ordered_indices.extend_from_slice(
-            &u32::to_ne_bytes(cursor.try_into()?));
-
-        ordered_indices.extend_from_slice(
-            &u32::to_ne_bytes((len + cursor).try_into()?));
-
-        cursor += len;
-    }
-
-    compacted.extend_from_slice(
-        &u32::to_ne_bytes(ordered_indices.len().try_into()?));
-
-    compacted.extend_from_slice(&ordered_indices);
+pub fn send_msg<T: QIO>(qrx: &mut T, msg: &Message) -> DRes<()> {
+    return write_frame(qrx, &msg.encode()?);
+}
 
-    return Ok(compacted);
+/// errors if the peer closed the pipe, use try_recv_msg
+/// where a close is an expected end of the session.
+/// An ERROR from the peer is returned as QzbError::Remote.
+/// The message has to fit limits, see protocol.rs.
+pub fn recv_msg<T: QIO>(
+    qrx: &mut T,
+    buf: &mut [u8; BLEN],
+    limits: &Limits,
+) -> DRes<Message> {
+    return try_recv_msg(qrx, buf, limits)?
+        .ok_or(QzbError::PeerClosed);
 }
 
-pub fn deindex_data(data: Vec<u8>) -> DRes<Vec<Vec<u8>>> {
-    let mut deindexed: Vec<Vec<u8>> = vec!();
-    let mut cursor = 4;
-    let mut endex = 12;
-    let mut nindices = u32::from_ne_bytes(data[..cursor].try_into()?);
-    let mut indices = Vec::with_capacity(nindices.try_into()?);
-    while nindices != 0 {
-        indices.push(
-            u32::from_ne_bytes(data[cursor..endex].try_into()?));
-        cursor += 4;
-        endex += 4;
-
-        indices.push(
-            u32::from_ne_bytes(data[cursor..endex].try_into()?));
-        cursor += 4;
-        endex += 4;
-        nindices -= 2;
-    } 
-
-    for (idx, val) in indices.iter().enumerate() {
-        let mut bounded_data = vec!();
-        bounded_data.extend_from_slice(
-            &data[(*val as usize)..(indices[idx+1] as usize)]);
-        deindexed.push(bounded_data);
-    }
+pub fn try_recv_msg<T: QIO>(
+    qrx: &mut T,
+    buf: &mut [u8; BLEN],
+    limits: &Limits,
+) -> DRes<Option<Message>> {
+    return recv_within(qrx, buf, limits, |tag| Message::max_frame_len(limits, tag));
+}
 
-    return Ok(deindexed);
+/// recv_msg for the first message of a session, only
+/// a HELLO or an ERROR can come then.
+pub fn recv_hello<T: QIO>(qrx: &mut T, buf: &mut [u8; BLEN]) -> DRes<Message> {
+    let limits = Limits::default();
+    let max_len = |tag| Message::max_frame_len(&limits, tag).min(ERROR_FRAME_LEN);
+    return recv_within(qrx, buf, &limits, max_len)?
+        .ok_or(QzbError::PeerClosed);
 }
 
-/// takes a qrx: impl QIO and a buffer
-/// to read the recv_seq into.
-#[macro_export]
-macro_rules! recv_seq {
-    ($qrx:expr, $buf:expr) => {
-        assert!(
-            1 == $qrx.read($buf)?
-            && $buf[0] == RECV_SEQ[0],
-            "{}", RECV_SEQ_ERR);
+fn recv_within<T: QIO>(
+    qrx: &mut T,
+    buf: &mut [u8; BLEN],
+    limits: &Limits,
+    max_len: impl Fn(u8) -> u64,
+) -> DRes<Option<Message>> {
+    return match read_frame(qrx, buf, max_len)? {
+        Some(frame) => match Message::decode(&frame, limits)? {
+            Message::Error { code, reason } => {
+                Err(QzbError::Remote { code, reason })?
+            }
+            msg => Ok(Some(msg)),
+        },
+        None => Ok(None),
     };
 }
 
-pub fn find_delim(buf: &[u8], pat: u8) -> Option<usize> {
-    for (i, char) in buf.iter().enumerate() {
-        if *char == pat {
-            return Some(i); } }
+/// writes a StateFile message below conf.state_dir,
+/// creating any missing parent directories. Like the
+/// two below the path is checked by sanitize::resolve_in,
+/// the write itself against conf.limits and usage, see
+/// sanitize::check_state_write. Files are written next
+/// to fpath and renamed over it, so nobody reading it
+/// ever sees half of one.
+pub fn write_state_file(
+    conf: &Conf,
+    usage: &mut Option<StateUsage>,
+    msg: Message,
+) -> DRes<()> {
+    let Message::StateFile { path, is_dir, contents } = msg else {
+        return Err(ProtocolError::UnexpectedMsg.into());
+    };
+
+    let state_dir = Path::new(&conf.state_dir);
+    check_path_limits(&conf.limits, &path)?;
+    let fpath = resolve_in(state_dir, &path)?;
+    check_state_write(&conf.limits, usage, state_dir, &fpath, contents.len())?;
+    if is_dir {
+        fs::create_dir_all(&fpath)?;
+    } else {
+        if let Some(parent) = fpath.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let part = part_path(&fpath)?;
+        let written = fs::write(&part, contents)
+            .and_then(|_| fs::rename(&part, &fpath));
+        if written.is_err() {
+            let _ = fs::remove_file(&part);
+        }
+        written?;
+    }
 
-    return None;
+    return Ok(());
 }
 
+/// true for the files write_state_file writes to
+/// before they are complete, those are never synced.
+pub fn is_partial(path: &Path) -> bool {
+    return path.file_name()
+        .is_some_and(|name| name.as_bytes().ends_with(PART_SUFFIX.as_bytes()));
+}
 
-/// bytes needs to be an accurate representation of the 
-/// number of bytes being written over the
-/// course of the entire message response chain that
-/// comprises the request, this function takes into account
-/// the length added by the num_reads array itself, 4 bytes.  
-pub fn num_reads_encode(bytes: usize) -> Result<([u8; 4], u32), TryFromIntError> {
-    let mut num_reads = ((bytes + 4) / BLEN).try_into()?;
-    if num_reads == 0 {
-        num_reads = 1;
-    }
+/// a hidden sibling of fpath no other write uses,
+/// sessions run as separate processes.
+fn part_path(fpath: &Path) -> DRes<PathBuf> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let Some(name) = fpath.file_name() else {
+        return Err(QzbError::Internal("write_state_file without a file name"));
+    };
 
-    #[cfg(target_endian = "big")]
-    return Ok((u32::to_be_bytes(num_reads), num_reads));
+    let mut part = OsString::from(".");
+    part.push(name);
+    part.push(format!(".{}.{}{PART_SUFFIX}", process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    return Ok(fpath.with_file_name(part));
+}
 
-    #[cfg(target_endian = "little")]
-    return Ok((u32::to_le_bytes(num_reads), num_reads));
+/// an exclusive flock on conf.state_dir until the returned
+/// file is dropped. Every session of a namespace shares its
+/// state dir, whatever reads a file to write it back holds
+/// this in between.
+pub fn lock_state_dir(conf: &Conf) -> DRes<fs::File> {
+    let dir = fs::File::open(&conf.state_dir)?;
+    dir.lock()?;
+    return Ok(dir);
 }
 
-pub fn num_reads_decode(bytes: [u8; 4]) -> u32 {
-    #[cfg(target_endian = "big")]
-    return u32::from_be_bytes(bytes);
+/// removes a file or a whole directory below conf.state_dir,
+/// a path that is already gone is not an error.
+pub fn remove_state_path(conf: &Conf, path: &Path) -> DRes<()> {
+    let fpath = resolve_in(Path::new(&conf.state_dir), path)?;
+    let res = if fpath.is_dir() {
+        fs::remove_dir_all(&fpath)
+    } else {
+        fs::remove_file(&fpath)
+    };
 
-    #[cfg(target_endian = "little")]
-    return u32::from_le_bytes(bytes);
+    match res {
+        Err(e) if e.kind() != NotFound => Err(e)?,
+        _ => return Ok(()),
+    }
 }
 
-/// returns the index of the first byte 
-/// of the slice, not set by the function,
-/// another way to think of this is that  
-/// the function returns the number of bytes
-/// that were set one based.
-pub fn set_slice(
-    slice: &mut [u8],
-    set: &[u8],
-) -> usize {
-    let mut i = 0usize;
-    for val in set {
-        slice[i] = *val; 
-        i += 1;
-    }  
-    return i;
+/// renames a file or directory below conf.state_dir,
+/// creating any missing parents of the destination.
+pub fn rename_state_path(conf: &Conf, from: &Path, to: &Path) -> DRes<()> {
+    let state_dir = Path::new(&conf.state_dir);
+    check_path_limits(&conf.limits, to)?;
+    let from = resolve_in(state_dir, from)?;
+    let to = resolve_in(state_dir, to)?;
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::rename(from, to)?;
+    return Ok(());
 }
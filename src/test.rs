@@ -1,6 +1,12 @@
 use std::{
-    path::PathBuf,
+    io,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    time::Duration,
+    path::{Path, PathBuf},
+    os::unix::fs::symlink,
     fs::{
+        read,
         read_dir,
         write,
         create_dir_all,
@@ -10,35 +16,28 @@ use std::{
 };
 use crate::{
     shared_fn::{
-        set_slice,
-        index_data,
-        deindex_data,
+        send_msg,
+        recv_msg,
     },
-    shared_consts::DRes,
-    client::StateFsTx,
+    digest::hash_bytes,
+    merge::{KeyFile, merge},
+    shared_consts::{DRes, BLEN},
+    error::{QzbError, ProtocolError, ErrorCode},
+    protocol::{Message, PROTOCOL_VERSION},
+    framing::{read_frame, write_frame, frame, take_frame},
+    notify::{NotifyMsg, NotifyClient, NOTIFY_VERSION, NOTIFY_FRAME_LEN},
+    mem_qio::{MemQIO, run_session, TEST_DOMAIN},
+    watcher::{StateWatcher, StateEvent, WatchEvents},
+    db::{self, DbTable, DbValue},
+    conf::{Conf, StateBackend, Limits, Policy, Operation, Namespaces},
+    client::{StateFsTx, Requester, BookTx, fetch_opened},
+    sanitize::{safe_name, safe_rel_path, resolve_in, check_state_write},
+    book_index::{BookEntry, BookIndex, DocType},
+    book_fs::{BookTree, NodeKind, ROOT_INO, FetchQueue, fetch_channel},
+    open_watch::{OpenWatcher, is_placeholder},
+    event_loop::{EventLoop, ShutdownSignal},
 };
 
-#[test]
-fn indexed_data_test() -> DRes<()> {
-    let mut test_data = vec!();
-
-    let var1 = vec![1,2,3,4];
-    let var2 = vec![4,3,2,1];
-    let var3 = vec![5,6,7,8];
-
-    test_data.push(var1);
-    test_data.push(var2);
-    test_data.push(var3);
-
-    let data = index_data(test_data.clone(), 0)?;
-    let tested_data = deindex_data(data)?;
-
-    assert_eq!(test_data, tested_data);
-
-    return Ok(());
-}
-
-
 const DIR_PATH: &str = "/tmp/qzb_testing_dir_89256";
 struct FileCleaner;
 impl Drop for FileCleaner {
@@ -79,7 +78,7 @@ fn state_fs_changes_test() -> DRes<()> {
     let changed_path = PathBuf::from(format!("{fbase_path}d"));
     write(&changed_path, fcont_changed)?; 
 
-    let changes_list_expected = vec!(changed_path);
+    let changes_list_expected = [changed_path];
     let changes_list = 
         StateFsTx::state_fs_changes(&mut fs_changes, read_dir(dir_path)?)?;
     for file in changes_list {
@@ -92,19 +91,1748 @@ fn state_fs_changes_test() -> DRes<()> {
 }
 
 #[test]
-fn set_slice_test() {
-    const SET_ERR: &str = 
-        "TEST: set_slice_test: Failed to set hello world! to HELLO WORLD!";
-    const NUM_BYTES_ERR: &str = 
-        "TEST: set_slice_test: Failed to return accurate number of bytes read"; 
+fn protocol_roundtrip_test() -> DRes<()> {
+    let msgs = vec!(
+        Message::hello(),
+        Message::hello_as(Some("work")),
+        Message::NoContent,
+        Message::Error {
+            code: ErrorCode::PathRejected,
+            reason: ProtocolError::UnexpectedMsg.to_string(),
+        },
+        Message::GetBookNames,
+        Message::BookNames(vec!(BookEntry {
+            path: PathBuf::from("sub/a.pdf"),
+            size: 1 << 40,
+            mtime: -1,
+            hash: hash_bytes(b"%PDF-a"),
+            doc_type: DocType::Cbz,
+        })),
+        Message::GetBook("a.pdf".to_owned()),
+        Message::Book { path: "sub/a.pdf".to_owned(), contents: vec![0, 159, 146, 150] },
+        Message::GetStateFiles(vec!()),
+        Message::GetStateFiles(vec!(
+            (PathBuf::from("bookmarks"), hash_bytes(b"[a.pdf]")),
+            (PathBuf::from("sub"), [0u8; 32]),
+        )),
+        Message::NumStateFiles(2),
+        Message::StateFile {
+            path: PathBuf::from("bookmarks"),
+            is_dir: false,
+            contents: b"[a.pdf]".to_vec(),
+        },
+        Message::StateFile {
+            path: PathBuf::from(OsStr::from_bytes(b"bad\xffname.sqlite")),
+            is_dir: false,
+            contents: vec![0x53, 0x51, 0x4c, 0xff, 0x00, 0xfe],
+        },
+        Message::RemoveState(PathBuf::from("sub/history")),
+        Message::RenameState { from: PathBuf::from("a"), to: PathBuf::from("b/c") },
+        Message::GetDbTables,
+        Message::DbTables(vec!(DbTable {
+            name: "fileinfo".to_owned(),
+            columns: vec!("file".to_owned(), "page".to_owned(), "zoom".to_owned()),
+            rows: vec!(
+                vec!(DbValue::Text("/a.pdf".to_owned()), DbValue::Integer(-3), DbValue::Real(1.25)),
+                vec!(DbValue::Blob(vec![0xff, 0x00]), DbValue::Null, DbValue::Null),
+            ),
+        })),
+    );
+
+    for msg in msgs {
+        assert_eq!(Message::decode(&msg.encode()?, &Limits::default())?, msg);
+    }
+
+    return Ok(());
+}
+
+#[test]
+fn protocol_rejects_malformed_test() -> DRes<()> {
+    let mut trailing = Message::GetBookNames.encode()?;
+    trailing.push(0);
+    assert!(Message::decode(&trailing, &Limits::default()).is_err());
+
+    let mut truncated = Message::GetBook("a.pdf".to_owned()).encode()?;
+    truncated.pop();
+    assert!(Message::decode(&truncated, &Limits::default()).is_err());
+
+    assert!(matches!(
+        Message::decode(b"~", &Limits::default()),
+        Err(QzbError::Protocol(ProtocolError::UnknownMsg))));
+    assert!(Message::decode(&[], &Limits::default()).is_err());
+
+    // every list is checked against the limits before it is decoded
+    let limits = Limits { max_files: 2, max_db_rows: 2, ..Limits::default() };
+    let manifest = Message::GetStateFiles(vec!((PathBuf::from("a"), hash_bytes(b"")); 3));
+    assert!(matches!(
+        Message::decode(&manifest.encode()?, &limits),
+        Err(QzbError::LimitExceeded { what: "number of state files", .. })));
+    let table = |rows| DbTable {
+        name: "bookmarks".to_owned(),
+        columns: vec!("file".to_owned()),
+        rows,
+    };
+    let tables = Message::DbTables(vec!(table(vec!(vec!(DbValue::Null); 3))));
+    assert!(matches!(
+        Message::decode(&tables.encode()?, &limits),
+        Err(QzbError::LimitExceeded { what: "number of rows", .. })));
+    let uneven = Message::DbTables(vec!(table(vec!(vec!(DbValue::Null; 2)))));
+    assert!(matches!(
+        Message::decode(&uneven.encode()?, &limits),
+        Err(QzbError::Protocol(ProtocolError::DbTable(_)))));
+
+    // only messages that carry a file get frames that large
+    let rm_tag = Message::RemoveState(PathBuf::from("a")).encode()?[0];
+    let sfile_tag = Message::StateFile { path: PathBuf::new(), is_dir: false, contents: vec!() }
+        .encode()?[0];
+    assert_eq!(Message::max_frame_len(&limits, rm_tag), limits.max_msg_len());
+    assert_eq!(Message::max_frame_len(&limits, sfile_tag), limits.max_frame_len());
+    let long = QzbError::Config("x".repeat(4096)).to_message().encode()?;
+    assert!(u64::try_from(long.len())? <= Message::max_frame_len(&limits, long[0]));
+
+    // whatever follows another version is skipped
+    let mut other = vec!(b'h');
+    other.extend_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+    other.extend_from_slice(b"\xff\xff unknown fields");
+    let other = Message::decode(&other, &Limits::default())?;
+    assert_eq!(other, Message::Hello { version: PROTOCOL_VERSION + 1, profile: None });
+    assert!(matches!(
+        other.check_hello(),
+        Err(QzbError::Protocol(ProtocolError::VersionMismatch { .. }))));
+    assert!(Message::GetBookNames.check_hello().is_err());
+    Message::hello().check_hello()?;
+
+    return Ok(());
+}
+
+/// a QIO that moves at most a few bytes per call, like
+/// a qrexec pipe is allowed to.
+struct Trickle {
+    data: Vec<u8>,
+    cursor: usize,
+}
+
+impl qrexec_binds::QIO for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let end = self.data.len().min(self.cursor + 3).min(self.cursor + buf.len());
+        let nb = end - self.cursor;
+        buf[..nb].copy_from_slice(&self.data[self.cursor..end]);
+        self.cursor = end;
+        return Ok(nb);
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let nb = buf.len().min(5);
+        self.data.extend_from_slice(&buf[..nb]);
+        return Ok(nb);
+    }
+}
+
+#[test]
+fn framing_split_io_test() -> DRes<()> {
+    let mut pipe = Trickle { data: vec!(), cursor: 0 };
+    let mut buf = [0u8; BLEN];
+    let big: Vec<u8> = (0..(BLEN * 3 + 17)).map(|x| x as u8).collect();
+
+    write_frame(&mut pipe, b"first")?;
+    write_frame(&mut pipe, &big)?;
+    write_frame(&mut pipe, &[])?;
+
+    assert_eq!(read_frame(&mut pipe, &mut buf, |_| u64::MAX)?, Some(b"first".to_vec()));
+    assert_eq!(read_frame(&mut pipe, &mut buf, |_| u64::MAX)?, Some(big));
+    assert_eq!(read_frame(&mut pipe, &mut buf, |_| u64::MAX)?, Some(vec!()));
+    assert_eq!(read_frame(&mut pipe, &mut buf, |_| u64::MAX)?, None);
+
+    let mut truncated = Trickle { data: vec!(), cursor: 0 };
+    write_frame(&mut truncated, b"cut short")?;
+    truncated.data.pop();
+    assert!(matches!(read_frame(&mut truncated, &mut buf, |_| u64::MAX), Err(QzbError::PeerClosed)));
+
+    // refused from the header and the tag alone
+    let mut huge = Trickle { data: [&u64::MAX.to_le_bytes()[..], b"6"].concat(), cursor: 0 };
+    assert!(matches!(
+        read_frame(&mut huge, &mut buf, |_| BLEN as u64),
+        Err(QzbError::LimitExceeded { what: "frame length", .. })));
+
+    return Ok(());
+}
+
+struct DirCleaner(String);
+impl Drop for DirCleaner {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+/// the paths of a BOOKNAMES listing
+fn listed(msg: Message) -> Vec<String> {
+    let Message::BookNames(books) = msg else {
+        panic!("not a listing: {msg:?}");
+    };
+
+    return books.into_iter()
+        .map(|book| book.path.to_string_lossy().into_owned())
+        .collect();
+}
+
+/// builds a server and a client Conf rooted in their own
+/// directories below /tmp/qzb_testing_e2e_<name>.
+fn e2e_confs(name: &str) -> DRes<(Conf, Conf, DirCleaner)> {
+    let root = format!("/tmp/qzb_testing_e2e_{name}");
+    let _ = remove_dir_all(&root);
+
+    let mut confs = vec!();
+    for model in ["server", "client"] {
+        let conf = Conf {
+            state_dir: format!("{root}/{model}/state"),
+            book_dir: format!("{root}/{model}/books"),
+            model: model.to_owned(),
+            target_vm: "vault".to_owned(),
+            state_backend: StateBackend::Plain,
+            slice_state: false,
+            limits: Limits::default(),
+            policies: HashMap::new(),
+            default_policy: Policy::default(),
+            namespaces: Namespaces::default(),
+            profile: None,
+            book_formats: DocType::SUPPORTED.to_vec(),
+            fuse_cache: None,
+            index_cache: None,
+            watch_opens: false,
+        };
+        create_dir_all(&conf.state_dir)?;
+        create_dir_all(&conf.book_dir)?;
+        confs.push(conf);
+    }
+
+    let client = confs.pop().unwrap();
+    let server = confs.pop().unwrap();
+    return Ok((server, client, DirCleaner(root)));
+}
+
+#[test]
+fn e2e_initialize_files_test() -> DRes<()> {
+    let (sconf, cconf, _cleaner) = e2e_confs("init")?;
+    write(format!("{}/a.pdf", sconf.book_dir), b"%PDF-a")?;
+    write(format!("{}/b.djvu", sconf.book_dir), b"AT&TFORM")?;
+    write(format!("{}/bookmarks", sconf.state_dir), b"[a.pdf]")?;
+    create_dir_all(format!("{}/sub", sconf.state_dir))?;
+    write(format!("{}/sub/history", sconf.state_dir), b"[b.djvu]")?;
+
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        return rq.initialize_files(&cconf);
+    })?;
+
+    // books only show up as placeholders until requested
+    assert_eq!(read(format!("{}/a.pdf", cconf.book_dir))?, [0; 6]);
+    assert_eq!(read(format!("{}/b.djvu", cconf.book_dir))?, [0; 8]);
+    assert_eq!(read(format!("{}/bookmarks", cconf.state_dir))?, b"[a.pdf]");
+    assert_eq!(read(format!("{}/sub/history", cconf.state_dir))?, b"[b.djvu]");
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_get_book_test() -> DRes<()> {
+    let (sconf, cconf, _cleaner) = e2e_confs("book")?;
+    let book: Vec<u8> = (0..(BLEN * 2 + 5)).map(|x| x as u8).collect();
+    write(format!("{}/a.pdf", sconf.book_dir), &book)?;
+
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        rq.get_book(&cconf, "a.pdf")?;
+        let missing = rq.get_book(&cconf, "missing.pdf").unwrap_err();
+        assert!(matches!(missing, QzbError::Remote { code: ErrorCode::BookNotFound, .. }));
+        assert!(missing.is_recoverable());
+
+        // the session goes on after an ERROR
+        rq.get_book(&cconf, "a.pdf")?;
+        return Ok(());
+    })?;
+
+    assert_eq!(read(format!("{}/a.pdf", cconf.book_dir))?, book);
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_send_file_test() -> DRes<()> {
+    let (sconf, cconf, _cleaner) = e2e_confs("upload")?;
+    let server_state = sconf.state_dir.clone();
+    create_dir_all(format!("{}/sub", cconf.state_dir))?;
+    write(format!("{}/sub/bookmarks", cconf.state_dir), b"[a.pdf]\n1=2\n")?;
+
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        // the directory and the file go out as one batch
+        return rq.send_files(&cconf, vec!(
+            PathBuf::from(format!("{}/sub", cconf.state_dir)),
+            PathBuf::from(format!("{}/sub/bookmarks", cconf.state_dir)),
+        ));
+    })?;
+
+    assert_eq!(read(format!("{server_state}/sub/bookmarks"))?, b"[a.pdf]\n1=2\n");
+
+    return Ok(());
+}
+
+#[test]
+fn state_watcher_test() -> DRes<()> {
+    let root = "/tmp/qzb_testing_watcher";
+    let _ = remove_dir_all(root);
+    let _cleaner = DirCleaner(root.to_owned());
+    create_dir_all(root)?;
+
+    let mut watcher = StateWatcher::new(root)?;
+    let WatchEvents::Changed(idle) = watcher.wait(Duration::from_millis(10))? else {
+        panic!("TEST: state_watcher_test: unexpected overflow");
+    };
+    assert!(idle.is_empty());
+
+    // a burst of writes to the same file is one change and a
+    // directory created with contents reports the contents too.
+    for _ in 0..5 {
+        write(format!("{root}/history"), b"[a.pdf]")?;
+    }
+    create_dir_all(format!("{root}/sub/deeper"))?;
+    write(format!("{root}/sub/deeper/bookmarks"), b"[b.pdf]")?;
+
+    let WatchEvents::Changed(changed) = watcher.wait(Duration::from_secs(1))? else {
+        panic!("TEST: state_watcher_test: unexpected overflow");
+    };
+
+    let history = PathBuf::from(format!("{root}/history"));
+    let bookmarks = PathBuf::from(format!("{root}/sub/deeper/bookmarks"));
+    let written_history = StateEvent::Written(history.clone());
+    assert_eq!(changed.iter().filter(|x| **x == written_history).count(), 1);
+    assert!(changed.contains(&StateEvent::Written(bookmarks.clone())));
+
+    // the new subdirectory is watched from now on
+    write(&bookmarks, b"[b.pdf]\n1=4")?;
+    let WatchEvents::Changed(changed) = watcher.wait(Duration::from_secs(1))? else {
+        panic!("TEST: state_watcher_test: unexpected overflow");
+    };
+    assert_eq!(changed, vec!(StateEvent::Written(bookmarks.clone())));
+
+    std::fs::rename(format!("{root}/sub"), format!("{root}/moved"))?;
+    std::fs::remove_file(&history)?;
+    let WatchEvents::Changed(changed) = watcher.wait(Duration::from_secs(1))? else {
+        panic!("TEST: state_watcher_test: unexpected overflow");
+    };
+    assert_eq!(changed, vec!(
+        StateEvent::Renamed {
+            from: PathBuf::from(format!("{root}/sub")),
+            to: PathBuf::from(format!("{root}/moved")),
+        },
+        StateEvent::Removed(history),
+    ));
+
+    // the watches below the renamed directory follow it
+    write(format!("{root}/moved/deeper/bookmarks"), b"[b.pdf]")?;
+    let WatchEvents::Changed(changed) = watcher.wait(Duration::from_secs(1))? else {
+        panic!("TEST: state_watcher_test: unexpected overflow");
+    };
+    assert_eq!(changed, vec!(StateEvent::Written(
+        PathBuf::from(format!("{root}/moved/deeper/bookmarks")))));
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_remove_rename_test() -> DRes<()> {
+    let (sconf, cconf, _cleaner) = e2e_confs("rmmv")?;
+    let server_state = sconf.state_dir.clone();
+    create_dir_all(format!("{server_state}/old/inner"))?;
+    write(format!("{server_state}/old/inner/history"), b"[a.pdf]")?;
+    write(format!("{server_state}/bookmarks"), b"[a.pdf]")?;
+
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        let state = |x: &str| PathBuf::from(format!("{}/{x}", cconf.state_dir));
+        rq.handshake(None)?;
+        rq.send_rename(&cconf, &state("old"), &state("new/dir"))?;
+        rq.send_remove(&cconf, &state("bookmarks"))?;
+        // already gone on the server, not an error
+        return rq.send_remove(&cconf, &state("never_synced"));
+    })?;
+
+    assert_eq!(read(format!("{server_state}/new/dir/inner/history"))?, b"[a.pdf]");
+    assert!(!PathBuf::from(format!("{server_state}/old")).exists());
+    assert!(!PathBuf::from(format!("{server_state}/bookmarks")).exists());
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_binary_state_test() -> DRes<()> {
+    let (sconf, cconf, _cleaner) = e2e_confs("binary")?;
+    let server_state = sconf.state_dir.clone();
+    let sqlite: Vec<u8> = (0..=255u8).rev().collect();
+    let odd_name = OsStr::from_bytes(b"hist\xfe\xff");
+    let client_path = PathBuf::from(&cconf.state_dir).join(odd_name);
+    write(format!("{}/bookmarks.sqlite", cconf.state_dir), &sqlite)?;
+    write(&client_path, &sqlite[..7])?;
+
+    // binary contents must not trip up change detection either
+    let mut fs_states = HashMap::new();
+    let changed = StateFsTx::state_fs_changes(
+        &mut fs_states, read_dir(&cconf.state_dir)?)?;
+    assert_eq!(changed.len(), 2);
+
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        for path in changed {
+            rq.send_files(&cconf, vec!(path))?;
+        }
+        return Ok(());
+    })?;
+
+    assert_eq!(read(format!("{server_state}/bookmarks.sqlite"))?, sqlite);
+    assert_eq!(read(PathBuf::from(&server_state).join(odd_name))?, &sqlite[..7]);
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_state_manifest_test() -> DRes<()> {
+    let (sconf, cconf, _cleaner) = e2e_confs("manifest")?;
+    write(format!("{}/bookmarks", sconf.state_dir), b"[a.pdf]")?;
+    write(format!("{}/history", sconf.state_dir), b"[a.pdf]\npage=7")?;
+
+    // the client is a cached dispvm with an up to date
+    // bookmarks file and an outdated history file.
+    write(format!("{}/bookmarks", cconf.state_dir), b"[a.pdf]")?;
+    write(format!("{}/history", cconf.state_dir), b"[a.pdf]\npage=1")?;
+
+    run_session(sconf, |qrx| {
+        let mut rbuf = [0u8; BLEN];
+        Requester::new(&mut *qrx).handshake(None)?;
+        send_msg(qrx, &Message::GetStateFiles(vec!(
+            (PathBuf::from("bookmarks"), hash_bytes(b"[a.pdf]")),
+            (PathBuf::from("history"), hash_bytes(b"[a.pdf]\npage=1")),
+        )))?;
+
+        assert_eq!(recv_msg(qrx, &mut rbuf, &Limits::default())?, Message::NumStateFiles(1));
+        assert_eq!(recv_msg(qrx, &mut rbuf, &Limits::default())?, Message::StateFile {
+            path: PathBuf::from("history"),
+            is_dir: false,
+            contents: b"[a.pdf]\npage=7".to_vec(),
+        });
+
+        // the real client builds the same manifest itself
+        return Requester::new(qrx).initialize_files(&cconf);
+    })?;
+
+    assert_eq!(read(format!("{}/history", cconf.state_dir))?, b"[a.pdf]\npage=7");
+
+    // rewriting identical contents is not a change
+    let mut fs_states = HashMap::new();
+    let _ = StateFsTx::state_fs_changes(&mut fs_states, read_dir(&cconf.state_dir)?)?;
+    write(format!("{}/history", cconf.state_dir), b"[a.pdf]\npage=7")?;
+    let changed = StateFsTx::state_fs_changes(&mut fs_states, read_dir(&cconf.state_dir)?)?;
+    assert!(changed.is_empty());
+
+    return Ok(());
+}
+
+#[test]
+fn hostile_state_batch_test() -> DRes<()> {
+    let (_sconf, cconf, _cleaner) = e2e_confs("hostile_batch")?;
+    let (mut qrx, mut server) = MemQIO::pair()?;
+
+    // a server that nests NUM_SFILES inside its own batch
+    let handle = std::thread::spawn(move || -> DRes<()> {
+        let mut rbuf = [0u8; BLEN];
+        let _ = recv_msg(&mut server, &mut rbuf, &Limits::default())?;
+        send_msg(&mut server, &Message::hello())?;
+        let _ = recv_msg(&mut server, &mut rbuf, &Limits::default())?;
+        for _ in 0..64 {
+            send_msg(&mut server, &Message::NumStateFiles(1))?;
+        }
+        return Ok(());
+    });
+
+    let mut rq = Requester::new(&mut qrx);
+    rq.handshake(None)?;
+    assert!(matches!(
+        rq.get_state(&cconf),
+        Err(QzbError::Protocol(ProtocolError::UnexpectedMsg)),
+    ));
+    handle.join().unwrap()?;
+
+    return Ok(());
+}
+
+#[test]
+fn keyfile_merge_test() {
+    let base = KeyFile::parse("[/books/a.pdf]\n1=10\n2=20\n\n[/books/b.pdf]\n1=3\n");
+    // ours: another vm added a bookmark and deleted b.pdf
+    let ours = KeyFile::parse("[/books/a.pdf]\n1=10\n2=20\n3=30\n");
+    // theirs: added a different bookmark, moved 2, added c.pdf
+    let theirs = KeyFile::parse(
+        "[/books/a.pdf]\n1=10\n2=25\n4=40\n\n[/books/b.pdf]\n1=3\n\n[/books/c.pdf]\n1=1\n");
+
+    let merged = merge(&base, &ours, &theirs);
+    assert_eq!(merged.serialize(),
+        "[/books/a.pdf]\n1=10\n2=25\n3=30\n4=40\n\n[/books/c.pdf]\n1=1\n");
+
+    // merging with nothing changed on our side is theirs
+    assert_eq!(merge(&base, &base, &theirs), theirs);
+    assert_eq!(KeyFile::parse(&theirs.serialize()), theirs);
+}
+
+#[test]
+fn e2e_concurrent_bookmarks_test() -> DRes<()> {
+    let (sconf, cconf, _cleaner) = e2e_confs("merge")?;
+    let sconf_b = sconf.clone();
+    let server_state = sconf.state_dir.clone();
+    let mut cconf_b = cconf.clone();
+    cconf_b.state_dir = format!("{}_b", cconf.state_dir);
+    let _cleaner_b = DirCleaner(cconf_b.state_dir.clone());
+    create_dir_all(&cconf_b.state_dir)?;
+    write(format!("{server_state}/bookmarks"), b"[/books/a.pdf]\n1=1\n")?;
+
+    // both vms fetch the same version before either uploads
+    run_session(sconf, |qa| {
+        let mut rq = Requester::new(qa);
+        rq.handshake(None)?;
+        rq.initialize_files(&cconf)?;
+
+        return run_session(sconf_b, |qb| {
+            let mut rq_b = Requester::new(qb);
+            rq_b.handshake(None)?;
+            rq_b.initialize_files(&cconf_b)?;
+
+            let bookmarks = format!("{}/bookmarks", cconf.state_dir);
+            write(&bookmarks, b"[/books/a.pdf]\n1=1\nch2=12\n")?;
+            rq.send_files(&cconf, vec!(PathBuf::from(&bookmarks)))?;
+            // any round trip makes sure the upload was handled
+            rq.initialize_files(&cconf)?;
+
+            let bookmarks_b = format!("{}/bookmarks", cconf_b.state_dir);
+            write(&bookmarks_b, b"[/books/a.pdf]\n1=1\nch5=40\n")?;
+            return rq_b.send_files(&cconf_b, vec!(PathBuf::from(&bookmarks_b)));
+        });
+    })?;
+
+    assert_eq!(
+        read(format!("{server_state}/bookmarks"))?,
+        b"[/books/a.pdf]\n1=1\nch2=12\nch5=40\n");
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_interleaved_uploads_test() -> DRes<()> {
+    const ROUNDS: usize = 100;
+    let (sconf, cconf, _cleaner) = e2e_confs("interleaved")?;
+    let server_state = sconf.state_dir.clone();
+    write(format!("{server_state}/bookmarks"), b"[/books/a.pdf]\n1=1\n")?;
+
+    // two vms keep adding bookmarks at the same time,
+    // each session merges against what the other wrote
+    let vm = |name: &'static str| {
+        let sconf = sconf.clone();
+        let mut cconf = cconf.clone();
+        cconf.state_dir = format!("{}_{name}", cconf.state_dir);
+        return move || -> DRes<()> {
+            let _cleaner = DirCleaner(cconf.state_dir.clone());
+            create_dir_all(&cconf.state_dir)?;
+            return run_session(sconf, |qrx| {
+                let mut rq = Requester::new(qrx);
+                rq.handshake(None)?;
+                rq.get_state(&cconf)?;
+                let bookmarks = PathBuf::from(&cconf.state_dir).join("bookmarks");
+                let mut contents = read(&bookmarks)?;
+                for i in 0..ROUNDS {
+                    contents.extend_from_slice(format!("{name}{i}={i}\n").as_bytes());
+                    write(&bookmarks, &contents)?;
+                    rq.send_files(&cconf, vec!(bookmarks.clone()))?;
+                }
+                return Ok(());
+            });
+        };
+    };
+    let a = std::thread::spawn(vm("a"));
+    let b = std::thread::spawn(vm("b"));
+    a.join().unwrap()?;
+    b.join().unwrap()?;
+
+    let stored = String::from_utf8(read(format!("{server_state}/bookmarks"))?).unwrap();
+    let lines: Vec<_> = stored.lines().collect();
+    for i in 0..ROUNDS {
+        for name in ["a", "b"] {
+            assert!(lines.contains(&format!("{name}{i}={i}").as_str()), "{name}{i} lost");
+        }
+    }
+    // every write was renamed into place
+    assert_eq!(read_dir(&server_state)?.count(), 1);
+
+    return Ok(());
+}
+
+fn bookmark_rows(conf: &Conf) -> DRes<Vec<Vec<DbValue>>> {
+    let tables = db::read_tables(&db::open(&db::db_path(conf))?)?;
+    return Ok(tables.into_iter()
+        .find(|table| table.name == "bookmarks")
+        .map(|table| table.rows)
+        .unwrap_or_default());
+}
+
+fn bookmark(file: &str, id: &str, page: i64) -> Vec<DbValue> {
+    return vec!(
+        DbValue::Text(file.to_owned()),
+        DbValue::Text(id.to_owned()),
+        DbValue::Integer(page),
+        DbValue::Real(0.0),
+        DbValue::Real(0.5),
+    );
+}
+
+#[test]
+fn e2e_sqlite_test() -> DRes<()> {
+    let (mut sconf, mut cconf, _cleaner) = e2e_confs("sqlite")?;
+    sconf.state_backend = StateBackend::Sqlite;
+    cconf.state_backend = StateBackend::Sqlite;
+    let server_conf = sconf.clone();
+
+    let insert = "INSERT INTO bookmarks VALUES (?1, ?2, ?3, 0.0, 0.5)";
+    let sdb = db::open(&db::db_path(&sconf))?;
+    let _ = sdb.execute(insert, ("/a.pdf", "old", 1))?;
+    let _ = sdb.execute(insert, ("/a.pdf", "keep", 2))?;
+    write(format!("{}/input_history", sconf.state_dir), b"[history]")?;
+
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        rq.initialize_files(&cconf)?;
+
+        // another vm adds a row after this client fetched
+        let _ = sdb.execute(insert, ("/b.pdf", "other", 3))?;
+
+        let mut rows = bookmark_rows(&cconf)?;
+        rows.retain(|row| row[1] != DbValue::Text("old".to_owned()));
+        rows.push(bookmark("/a.pdf", "new", 9));
+        let tables = vec!(DbTable {
+            name: "bookmarks".to_owned(),
+            columns: ["file", "id", "page", "hadj_ratio", "vadj_ratio"]
+                .map(str::to_owned).to_vec(),
+            rows,
+        });
+        rq.send_db(&cconf, tables)?;
+
+        // anything zathura doesn't have is refused
+        let res = rq.send_db(&cconf, vec!(DbTable {
+            name: "sqlite_master".to_owned(),
+            columns: vec!(),
+            rows: vec!(),
+        }));
+        let reason = ProtocolError::DbTable("sqlite_master".to_owned()).to_string();
+        assert!(matches!(
+            res,
+            Err(QzbError::Remote { code: ErrorCode::Protocol, reason: x }) if x == reason));
+
+        // nor can the database be replaced or removed as a file
+        let db_file = db::db_path(&cconf);
+        for res in [rq.send_files(&cconf, vec!(db_file.clone())), rq.send_remove(&cconf, &db_file)] {
+            assert!(matches!(res, Err(QzbError::Remote { code: ErrorCode::PathRejected, .. })));
+        }
+        return Ok(());
+    })?;
+
+    let mut client_rows = bookmark_rows(&cconf)?;
+    client_rows.sort_by_key(|row| format!("{row:?}"));
+    assert_eq!(client_rows, vec!(bookmark("/a.pdf", "keep", 2), bookmark("/a.pdf", "old", 1)));
+    assert_eq!(read(format!("{}/input_history", cconf.state_dir))?, b"[history]");
+
+    let mut server_rows = bookmark_rows(&server_conf)?;
+    server_rows.sort_by_key(|row| format!("{row:?}"));
+    assert_eq!(server_rows, vec!(
+        bookmark("/a.pdf", "keep", 2),
+        bookmark("/a.pdf", "new", 9),
+        bookmark("/b.pdf", "other", 3),
+    ));
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_fetch_state_test() -> DRes<()> {
+    let (sconf, cconf, _cleaner) = e2e_confs("fetch_state")?;
+    let server_state = sconf.state_dir.clone();
+    write(format!("{server_state}/notes"), b"server")?;
+
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        rq.initialize_files(&cconf)?;
+        let mut state_tx = StateFsTx::new(&cconf)?;
+
+        // a local edit the loop hasn't synced yet goes out first
+        write(format!("{}/local", cconf.state_dir), b"local")?;
+        write(format!("{server_state}/notes"), b"another vm")?;
+        state_tx.fetch(&mut rq, &cconf)?;
+        assert_eq!(read(format!("{server_state}/local"))?, b"local");
+        assert_eq!(read(format!("{}/notes", cconf.state_dir))?, b"another vm");
+
+        // and what was fetched isn't echoed back
+        write(format!("{server_state}/notes"), b"newer")?;
+        return state_tx.sync(&mut rq, &cconf, Duration::from_millis(300));
+    })?;
+
+    assert_eq!(read(format!("{server_state}/notes"))?, b"newer");
+    return Ok(());
+}
+
+#[test]
+fn e2e_slice_state_test() -> DRes<()> {
+    let (mut sconf, cconf, _cleaner) = e2e_confs("slice")?;
+    sconf.slice_state = true;
+    let server_state = sconf.state_dir.clone();
+    let history = format!("{}/history", cconf.state_dir);
+    write(format!("{}/a.pdf", sconf.book_dir), b"%PDF-a")?;
+    write(
+        format!("{server_state}/history"),
+        b"[/vm1/a.pdf]\npage=1\n\n[/vm2/b.pdf]\npage=7\n")?;
+    write(format!("{server_state}/input-history"), b"[/vm2/b.pdf]\n")?;
+
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        rq.initialize_files(&cconf)?;
+        assert_eq!(read(&history)?, b"");
+
+        rq.get_book(&cconf, "a.pdf")?;
+        rq.get_state(&cconf)?;
+        assert_eq!(read(&history)?, b"[/vm1/a.pdf]\npage=1\n");
+        // can't be sliced, so it isn't sent at all
+        assert!(!Path::new(&format!("{}/input-history", cconf.state_dir)).exists());
+
+        write(&history, b"[/vm3/a.pdf]\npage=4\n")?;
+        rq.send_files(&cconf, vec!(PathBuf::from(&history)))?;
+
+        // can't be merged, stored as is it would drop b.pdf
+        write(&history, b"\xff[/vm3/a.pdf]\n")?;
+        assert!(matches!(
+            rq.send_files(&cconf, vec!(PathBuf::from(&history))),
+            Err(QzbError::Remote { code: ErrorCode::Protocol, .. })));
+        return Ok(());
+    })?;
+
+    // b.pdf was never sent so the upload can't touch it
+    assert_eq!(
+        read(format!("{server_state}/history"))?,
+        b"[/vm2/b.pdf]\npage=7\n\n[/vm3/a.pdf]\npage=4\n");
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_dispatch_test() -> DRes<()> {
+    let (sconf, _, _cleaner) = e2e_confs("dispatch")?;
+    let server_state = sconf.state_dir.clone();
+    write(format!("{}/a.pdf", sconf.book_dir), b"%PDF-a")?;
+    write(format!("{server_state}/plain"), b"")?;
+
+    run_session(sconf, |qrx| {
+        let mut rbuf = [0u8; BLEN];
+        Requester::new(&mut *qrx).handshake(None)?;
+
+        // rejected requests don't end the session
+        write_frame(qrx, b"~")?;
+        let reason = ProtocolError::UnknownMsg.to_string();
+        assert!(matches!(
+            recv_msg(qrx, &mut rbuf, &Limits::default()),
+            Err(QzbError::Remote { code: ErrorCode::Protocol, reason: x }) if x == reason));
+        send_msg(qrx, &Message::Book { path: "b.pdf".to_owned(), contents: b"%PDF-b".to_vec() })?;
+        let reason = ProtocolError::UnexpectedMsg.to_string();
+        assert!(matches!(
+            recv_msg(qrx, &mut rbuf, &Limits::default()),
+            Err(QzbError::Remote { code: ErrorCode::Protocol, reason: x }) if x == reason));
+
+        // so do failed ones, the whole batch is read anyway
+        send_msg(qrx, &Message::RenameState {
+            from: PathBuf::from("missing"),
+            to: PathBuf::from("elsewhere"),
+        })?;
+        assert!(matches!(
+            recv_msg(qrx, &mut rbuf, &Limits::default()),
+            Err(QzbError::Remote { code: ErrorCode::Io, .. })));
+        send_msg(qrx, &Message::NumStateFiles(2))?;
+        send_msg(qrx, &Message::StateFile {
+            path: PathBuf::from("plain/file"),
+            is_dir: false,
+            contents: vec!(),
+        })?;
+        send_msg(qrx, &Message::StateFile {
+            path: PathBuf::from("input_history"),
+            is_dir: false,
+            contents: b"[batch]".to_vec(),
+        })?;
+        assert!(matches!(
+            recv_msg(qrx, &mut rbuf, &Limits::default()),
+            Err(QzbError::Remote { code: ErrorCode::Io, .. })));
+
+        send_msg(qrx, &Message::NumStateFiles(2))?;
+        send_msg(qrx, &Message::StateFile {
+            path: PathBuf::from("sub"),
+            is_dir: true,
+            contents: vec!(),
+        })?;
+        send_msg(qrx, &Message::StateFile {
+            path: PathBuf::from("sub/input_history"),
+            is_dir: false,
+            contents: b"[commands]".to_vec(),
+        })?;
+        assert_eq!(recv_msg(qrx, &mut rbuf, &Limits::default())?, Message::NoContent);
+
+        send_msg(qrx, &Message::GetBookNames)?;
+        assert_eq!(listed(recv_msg(qrx, &mut rbuf, &Limits::default())?), ["a.pdf"]);
+        return Ok(());
+    })?;
+
+    assert_eq!(read(format!("{server_state}/sub/input_history"))?, b"[commands]");
+    assert_eq!(read(format!("{server_state}/input_history"))?, b"[batch]");
+    return Ok(());
+}
+
+#[test]
+fn sanitize_hostile_names_test() -> DRes<()> {
+    assert_eq!(safe_name("a.pdf")?, "a.pdf");
+    assert_eq!(safe_name("..a.pdf")?, "..a.pdf");
+    for name in ["", ".", "..", "../a.pdf", "/a.pdf", "sub/a.pdf", "a.pdf\0"] {
+        assert!(
+            matches!(safe_name(name), Err(QzbError::PathSafety { .. })),
+            "accepted {name:?}");
+    }
+
+    assert!(safe_rel_path(Path::new("sub/history")).is_ok());
+    let hostile = [
+        "", "/", "/etc/passwd", "..", "../../home/user/.bashrc", "sub/../../x",
+        "sub/..", ".", "./history", "sub/./history", "sub//history", "sub/",
+        "history\0",
+    ];
+    for path in hostile {
+        assert!(
+            matches!(safe_rel_path(Path::new(path)), Err(QzbError::PathSafety { .. })),
+            "accepted {path:?}");
+    }
+
+    return Ok(());
+}
+
+#[test]
+fn state_usage_test() -> DRes<()> {
+    let root = PathBuf::from(format!("{DIR_PATH}_usage"));
+    let _cleaner = DirCleaner(root.to_string_lossy().into_owned());
+    create_dir_all(&root)?;
+    write(root.join("a"), b"xxxx")?;
+    let limits = Limits { max_files: 4, max_state_size: 12, ..Limits::default() };
+
+    // the walk only happens once, every write that passes
+    // is counted from then on, its new parents included.
+    let mut usage = None;
+    check_state_write(&limits, &mut usage, &root, &root.join("b/c"), 4)?;
+    create_dir_all(root.join("b"))?;
+    write(root.join("b/c"), b"xxxx")?;
+    check_state_write(&limits, &mut usage, &root, &root.join("a"), 8)?;
+    write(root.join("a"), b"xxxxxxxx")?;
+    assert!(matches!(
+        check_state_write(&limits, &mut usage, &root, &root.join("d"), 1),
+        Err(QzbError::LimitExceeded { what: "state size", .. })));
+    assert!(matches!(
+        check_state_write(&limits, &mut usage, &root, &root.join("b/e/f"), 0),
+        Err(QzbError::LimitExceeded { what: "number of state files", .. })));
+    check_state_write(&limits, &mut usage, &root, &root.join("b/e"), 0)?;
+
+    // a file it wasn't told about only counts after a new walk
+    write(root.join("g"), b"")?;
+    check_state_write(&limits, &mut usage, &root, &root.join("b/c"), 4)?;
+    let mut usage = None;
+    assert!(check_state_write(&limits, &mut usage, &root, &root.join("b/e"), 0).is_err());
+
+    return Ok(());
+}
+
+#[test]
+fn sanitize_symlink_escape_test() -> DRes<()> {
+    const ROOT: &str = "/tmp/qzb_testing_sanitize";
+    let _ = remove_dir_all(ROOT);
+    let _cleaner = DirCleaner(ROOT.to_owned());
+    let root = Path::new(ROOT).join("state");
+    create_dir_all(root.join("sub"))?;
+    create_dir_all(Path::new(ROOT).join("outside"))?;
+    symlink("../outside", root.join("link"))?;
+    symlink("/etc/passwd", root.join("sub/file"))?;
+
+    assert_eq!(resolve_in(&root, Path::new("sub/new"))?, root.join("sub/new"));
+    assert_eq!(resolve_in(&root, Path::new("missing/new"))?, root.join("missing/new"));
+    for path in ["link", "link/history", "sub/file"] {
+        assert!(
+            matches!(resolve_in(&root, Path::new(path)), Err(QzbError::PathSafety { .. })),
+            "accepted {path:?}");
+    }
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_hostile_paths_test() -> DRes<()> {
+    let (sconf, _, _cleaner) = e2e_confs("hostile")?;
+    let root = Path::new(&sconf.state_dir).parent().unwrap().to_owned();
+    write(root.join("secret"), b"secret")?;
+
+    run_session(sconf, |qrx| {
+        let mut rbuf = [0u8; BLEN];
+        Requester::new(&mut *qrx).handshake(None)?;
+
+        let requests = vec!(
+            Message::GetBook("../state/../../secret".to_owned()),
+            Message::StateFile {
+                path: PathBuf::from("../escaped"),
+                is_dir: false,
+                contents: b"pwned".to_vec(),
+            },
+            Message::StateFile {
+                path: PathBuf::from("/tmp/qzb_testing_e2e_hostile/escaped"),
+                is_dir: false,
+                contents: b"pwned".to_vec(),
+            },
+            Message::RemoveState(PathBuf::from("../secret")),
+            Message::RenameState {
+                from: PathBuf::from("../secret"),
+                to: PathBuf::from("stolen"),
+            },
+        );
+        for request in requests {
+            send_msg(qrx, &request)?;
+            assert!(
+                matches!(
+                    recv_msg(qrx, &mut rbuf, &Limits::default()),
+                    Err(QzbError::Remote { code: ErrorCode::PathRejected, .. })),
+                "accepted {request:?}");
+        }
+
+        return Ok(());
+    })?;
+
+    assert_eq!(read(root.join("secret"))?, b"secret");
+    assert!(!root.join("escaped").exists());
+    return Ok(());
+}
+
+#[test]
+fn e2e_limits_test() -> DRes<()> {
+    let (mut sconf, mut cconf, _cleaner) = e2e_confs("limits")?;
+    write(format!("{}/a.pdf", sconf.book_dir), vec!(0; 17))?;
+    let book_sconf = sconf.clone();
+    sconf.limits = Limits {
+        max_file_size: 16,
+        max_state_size: 24,
+        max_files: 3,
+        max_path_len: 12,
+        max_path_depth: 2,
+        max_db_rows: 8,
+    };
+    let server_state = sconf.state_dir.clone();
+    let limits = sconf.limits.clone();
+    let batch_sconf = sconf.clone();
+
+    let res = run_session(sconf, |qrx| {
+        let mut rbuf = [0u8; BLEN];
+        Requester::new(&mut *qrx).handshake(None)?;
+
+        let file = |path: &str, len| Message::StateFile {
+            path: PathBuf::from(path),
+            is_dir: false,
+            contents: vec!(b'x'; len),
+        };
+        let requests = vec!(
+            (file("big", 17), "file size"),
+            (file("a/b/c", 1), "path depth"),
+            (file("long_file_name", 1), "path length"),
+            (Message::RenameState {
+                from: PathBuf::from("a"),
+                to: PathBuf::from("a/b/c"),
+            }, "path depth"),
+        );
+        for (request, what) in requests {
+            send_msg(qrx, &request)?;
+            let res = recv_msg(qrx, &mut rbuf, &Limits::default());
+            assert!(
+                matches!(&res, Err(QzbError::Remote { code: ErrorCode::LimitExceeded, reason })
+                    if reason.starts_with(what)),
+                "{request:?} got {res:?}");
+        }
+
+        // the state dir fills up
+        for (path, len) in [("a", 16), ("b", 8)] {
+            send_msg(qrx, &file(path, len))?;
+            assert_eq!(recv_msg(qrx, &mut rbuf, &Limits::default())?, Message::NoContent);
+        }
+        send_msg(qrx, &file("c", 1))?;
+        assert!(matches!(
+            recv_msg(qrx, &mut rbuf, &Limits::default()),
+            Err(QzbError::Remote { code: ErrorCode::LimitExceeded, .. })));
+        // replacing a file only counts the difference
+        send_msg(qrx, &file("b", 4))?;
+        assert_eq!(recv_msg(qrx, &mut rbuf, &Limits::default())?, Message::NoContent);
+        send_msg(qrx, &file("c", 4))?;
+        assert_eq!(recv_msg(qrx, &mut rbuf, &Limits::default())?, Message::NoContent);
+        send_msg(qrx, &Message::StateFile {
+            path: PathBuf::from("d"),
+            is_dir: true,
+            contents: vec!(),
+        })?;
+        assert!(matches!(
+            recv_msg(qrx, &mut rbuf, &Limits::default()),
+            Err(QzbError::Remote { code: ErrorCode::LimitExceeded, .. })));
+
+        // too large to even be read, ends the session
+        write_frame(qrx, &vec!(0; (limits.max_frame_len() + 1) as usize))?;
+        return Ok(());
+    });
+    assert!(matches!(res, Err(QzbError::LimitExceeded { what: "frame length", .. })));
+
+    let mut names: Vec<_> = read_dir(&server_state)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<_>>()?;
+    names.sort();
+    assert_eq!(names, ["a", "b", "c"]);
+    assert_eq!(read(format!("{server_state}/b"))?, b"xxxx");
+
+    // the same inside a batch instead of an ERROR for it
+    let res = run_session(batch_sconf, |qrx| {
+        Requester::new(&mut *qrx).handshake(None)?;
+        send_msg(qrx, &Message::NumStateFiles(2))?;
+        write_frame(qrx, &vec!(0; (limits.max_frame_len() + 1) as usize))?;
+        return Ok(());
+    });
+    assert!(matches!(res, Err(QzbError::LimitExceeded { what: "frame length", .. })));
+
+    // the client holds the server to its own limits
+    cconf.limits = limits;
+    run_session(book_sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        // a book too large only drops out of the listing
+        rq.initialize_files(&cconf)?;
+        assert!(rq.index().entries.is_empty());
+        assert!(matches!(
+            rq.get_book(&cconf, "a.pdf"),
+            Err(QzbError::LimitExceeded { what: "file size", .. })));
+        return Ok(());
+    })?;
+    assert!(!Path::new(&format!("{}/a.pdf", cconf.book_dir)).exists());
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_read_only_policy_test() -> DRes<()> {
+    let (mut sconf, cconf, _cleaner) = e2e_confs("policy")?;
+    let server_state = sconf.state_dir.clone();
+    let history = format!("{}/history", cconf.state_dir);
+    write(format!("{}/a.pdf", sconf.book_dir), b"%PDF-a")?;
+    write(format!("{server_state}/history"), b"[a.pdf]")?;
+
+    // the default policy only applies to other vms
+    sconf.default_policy.fetch_books = false;
+    let _ = sconf.policies.insert(TEST_DOMAIN.to_owned(), Policy {
+        upload_state: false,
+        ..Policy::default()
+    });
+
+    let denied = |res: DRes<()>, op: Operation| {
+        let reason = QzbError::Denied(op).to_string();
+        return matches!(
+            res,
+            Err(QzbError::Remote { code: ErrorCode::PermissionDenied, reason: x }) if x == reason);
+    };
+
+    run_session(sconf, |qrx| {
+        let mut rbuf = [0u8; BLEN];
+        let mut rq = Requester::new(&mut *qrx);
+        rq.handshake(None)?;
+        rq.initialize_files(&cconf)?;
+        rq.get_book(&cconf, "a.pdf")?;
+
+        write(&history, b"[b.pdf]")?;
+        let upload = rq.send_files(&cconf, vec!(PathBuf::from(&history)));
+        assert!(denied(upload, Operation::UploadState));
+        assert!(denied(rq.send_remove(&cconf, Path::new(&history)), Operation::UploadState));
+
+        // a denied batch is still read to its end
+        send_msg(qrx, &Message::NumStateFiles(2))?;
+        for path in ["a", "b"] {
+            send_msg(qrx, &Message::StateFile {
+                path: PathBuf::from(path),
+                is_dir: false,
+                contents: vec!(),
+            })?;
+        }
+        let res = recv_msg(qrx, &mut rbuf, &Limits::default()).map(|_| ());
+        assert!(denied(res, Operation::UploadState));
+
+        send_msg(qrx, &Message::GetBookNames)?;
+        assert_eq!(listed(recv_msg(qrx, &mut rbuf, &Limits::default())?), ["a.pdf"]);
+        return Ok(());
+    })?;
+
+    assert_eq!(read(format!("{}/a.pdf", cconf.book_dir))?, b"%PDF-a");
+    assert_eq!(read(&history)?, b"[b.pdf]");
+    assert_eq!(read(format!("{server_state}/history"))?, b"[a.pdf]");
+    assert!(!Path::new(&format!("{server_state}/a")).exists());
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_denied_startup_test() -> DRes<()> {
+    let (mut sconf, mut cconf, _cleaner) = e2e_confs("denied_startup")?;
+    sconf.state_backend = StateBackend::Sqlite;
+    cconf.state_backend = StateBackend::Sqlite;
+    let server_state = sconf.state_dir.clone();
+    let history = format!("{}/history", cconf.state_dir);
+    write(format!("{}/a.pdf", sconf.book_dir), b"%PDF-a")?;
+    write(format!("{server_state}/history"), b"[a.pdf]")?;
+    let _ = sconf.policies.insert(TEST_DOMAIN.to_owned(), Policy {
+        list_books: false,
+        upload_state: false,
+        ..Policy::default()
+    });
+
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        // the state still comes down without a listing
+        rq.initialize_files(&cconf)?;
+        assert!(rq.index().entries.is_empty());
+        assert_eq!(read(&history)?, b"[a.pdf]");
+
+        // the dirty database and later changes aren't sent
+        let mut state_tx = StateFsTx::new(&cconf)?;
+        state_tx.sync(&mut rq, &cconf, Duration::ZERO)?;
+        write(&history, b"[b.pdf]")?;
+        state_tx.sync(&mut rq, &cconf, Duration::from_millis(300))?;
+        return state_tx.fetch(&mut rq, &cconf);
+    })?;
+
+    assert_eq!(read(format!("{server_state}/history"))?, b"[a.pdf]");
+    return Ok(());
+}
+
+#[test]
+fn e2e_namespaces_test() -> DRes<()> {
+    let (mut sconf, cconf, _cleaner) = e2e_confs("namespaces")?;
+    let ns_dir = format!("{}/../namespaces", sconf.state_dir);
+    write(format!("{}/input_history", sconf.state_dir), b"[shared.pdf]")?;
+    sconf.namespaces.dir = Some(ns_dir.clone());
+    let _ = sconf.namespaces.profiles.insert("work".to_owned(), "work".to_owned());
+    let _ = sconf.namespaces.profiles.insert("personal".to_owned(), "personal".to_owned());
+    let history = format!("{}/input_history", cconf.state_dir);
+
+    // each session starts out with an empty client state dir
+    // and replaces what it saw with contents
+    let session = |sconf: Conf, profile: Option<&str>, contents: &[u8]| {
+        let _ = remove_dir_all(&cconf.state_dir);
+        create_dir_all(&cconf.state_dir)?;
+        let mut seen = None;
+        run_session(sconf, |qrx| {
+            let mut rq = Requester::new(qrx);
+            rq.handshake(profile)?;
+            rq.get_state(&cconf)?;
+            seen = read(&history).ok();
+            write(&history, contents)?;
+            return rq.send_files(&cconf, vec!(PathBuf::from(&history)));
+        })?;
+        return Ok::<_, QzbError>(seen);
+    };
+    assert_eq!(session(sconf.clone(), Some("work"), b"[work.pdf]")?, None);
+    assert_eq!(session(sconf.clone(), Some("personal"), b"[personal.pdf]")?, None);
+    assert_eq!(session(sconf.clone(), Some("work"), b"[work.pdf]")?, Some(b"[work.pdf]".to_vec()));
+    let shared = session(sconf.clone(), Some("unknown"), b"[shared.pdf]")?;
+    assert_eq!(shared, Some(b"[shared.pdf]".to_vec()));
+
+    assert_eq!(read(format!("{ns_dir}/work/input_history"))?, b"[work.pdf]");
+    assert_eq!(read(format!("{ns_dir}/personal/input_history"))?, b"[personal.pdf]");
+    assert_eq!(read(format!("{}/input_history", sconf.state_dir))?, b"[shared.pdf]");
+
+    // a mapped vm can't pick another namespace
+    let _ = sconf.namespaces.vms.insert(TEST_DOMAIN.to_owned(), "work".to_owned());
+    let mapped = session(sconf.clone(), Some("personal"), b"[work.pdf]")?;
+    assert_eq!(mapped, Some(b"[work.pdf]".to_vec()));
+
+    sconf.namespaces.vms.clear();
+    sconf.namespaces.dir = None;
+    sconf.namespaces.default = "other".to_owned();
+    let res = session(sconf, None, b"");
+    assert!(matches!(res, Err(QzbError::Remote { code: ErrorCode::Internal, .. })));
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_book_index_test() -> DRes<()> {
+    let (sconf, cconf, _cleaner) = e2e_confs("book_index")?;
+    let sbooks = Path::new(&sconf.book_dir);
+    for dir in ["sub", "other", "deep/er"] {
+        create_dir_all(sbooks.join(dir))?;
+    }
+    write(sbooks.join("a.pdf"), b"%PDF-a")?;
+    write(sbooks.join("sub/b.pdf"), b"%PDF-sub")?;
+    write(sbooks.join("other/b.pdf"), b"%PDF-other")?;
+    write(sbooks.join("deep/er/c.djvu"), b"AT&TFORM")?;
+    symlink("/etc/passwd", sbooks.join("link.pdf"))?;
+    let cbooks = Path::new(&cconf.book_dir).to_owned();
+
+    run_session(sconf.clone(), |qrx| {
+        let mut rbuf = [0u8; BLEN];
+        let mut rq = Requester::new(&mut *qrx);
+        rq.handshake(None)?;
+        rq.initialize_files(&cconf)?;
+
+        // duplicate basenames need the relative path
+        let res = rq.get_book(&cconf, "b.pdf");
+        assert!(
+            matches!(&res, Err(QzbError::Remote { code: ErrorCode::BookNotFound, reason })
+                if reason.contains("other/b.pdf") && reason.contains("sub/b.pdf")),
+            "{res:?}");
+        rq.get_book(&cconf, "sub/b.pdf")?;
+        rq.get_book(&cconf, "c.djvu")?;
+        assert!(rq.get_book(&cconf, "link.pdf").is_err());
+
+        // picked up without a new listing
+        write(sbooks.join("sub/new.pdf"), b"%PDF-new")?;
+        rq.get_book(&cconf, "new.pdf")?;
+
+        send_msg(qrx, &Message::GetBookNames)?;
+        assert_eq!(
+            listed(recv_msg(qrx, &mut rbuf, &Limits::default())?),
+            ["a.pdf", "deep/er/c.djvu", "other/b.pdf", "sub/b.pdf", "sub/new.pdf"]);
+        return Ok(());
+    })?;
+
+    assert_eq!(read(cbooks.join("a.pdf"))?, [0; 6]);
+    assert_eq!(read(cbooks.join("other/b.pdf"))?, [0; 10]);
+    assert_eq!(read(cbooks.join("sub/b.pdf"))?, b"%PDF-sub");
+    assert_eq!(read(cbooks.join("deep/er/c.djvu"))?, b"AT&TFORM");
+    assert_eq!(read(cbooks.join("sub/new.pdf"))?, b"%PDF-new");
+    assert!(!cbooks.join("link.pdf").exists());
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_index_cache_test() -> DRes<()> {
+    let (mut sconf, _, _cleaner) = e2e_confs("index_cache")?;
+    sconf.index_cache = Some(format!("{}.index", sconf.book_dir));
+    let book = PathBuf::from(&sconf.book_dir).join("a.pdf");
+    write(&book, b"%PDF-a")?;
+    let mtime = std::fs::metadata(&book)?.modified()?;
+
+    let listed_hash = |sconf: Conf| {
+        let mut hash = None;
+        run_session(sconf, |qrx| {
+            let mut rbuf = [0u8; BLEN];
+            Requester::new(&mut *qrx).handshake(None)?;
+            send_msg(qrx, &Message::GetBookNames)?;
+            let Message::BookNames(books) = recv_msg(qrx, &mut rbuf, &Limits::default())? else {
+                return Err(ProtocolError::UnexpectedMsg.into());
+            };
+            hash = books.first().map(|book| book.hash);
+            return Ok(());
+        })?;
+        return Ok::<_, QzbError>(hash);
+    };
+    assert_eq!(listed_hash(sconf.clone())?, Some(hash_bytes(b"%PDF-a")));
+
+    // same size and mtime, a new session takes the cached hash
+    write(&book, b"%PDF-b")?;
+    std::fs::File::options().write(true).open(&book)?.set_modified(mtime)?;
+    assert_eq!(listed_hash(sconf.clone())?, Some(hash_bytes(b"%PDF-a")));
+
+    std::fs::File::options().write(true).open(&book)?
+        .set_modified(mtime + Duration::from_secs(1))?;
+    assert_eq!(listed_hash(sconf)?, Some(hash_bytes(b"%PDF-b")));
+
+    return Ok(());
+}
+
+#[test]
+fn doc_type_detect_test() -> DRes<()> {
+    const ROOT: &str = "/tmp/qzb_testing_doc_type";
+    let _ = remove_dir_all(ROOT);
+    create_dir_all(ROOT)?;
+    let _cleaner = DirCleaner(ROOT.to_owned());
 
-    let mut test_init = vec!();
-    test_init.extend_from_slice(b"hello world!");
+    let mut epub = b"PK\x03\x04".to_vec();
+    epub.resize(30, 0);
+    epub.extend_from_slice(b"mimetypeapplication/epub+zip");
+    let files: [(&str, &[u8], DocType); 8] = [
+        ("a.pdf", b"%PDF-1.7\n", DocType::Pdf),
+        ("a.djvu", b"AT&TFORM\0\0", DocType::Djvu),
+        ("a.ps", b"%!PS-Adobe-3.0", DocType::PostScript),
+        ("a.epub", &epub, DocType::Epub),
+        ("a.cbz", b"PK\x03\x04 images", DocType::Cbz),
+        ("a.zip", b"PK\x03\x04 images", DocType::Other),
+        // the extension alone doesn't make a pdf
+        ("fake.pdf", b"MZ", DocType::Other),
+        ("empty", b"", DocType::Other),
+    ];
+    for (name, contents, doc_type) in files {
+        let path = Path::new(ROOT).join(name);
+        write(&path, contents)?;
+        assert_eq!(DocType::detect(&path)?, doc_type, "{name}");
+    }
 
-    let mut exp_ret = vec!();
-    exp_ret.extend_from_slice(b"HELLO WORLD!");
+    return Ok(());
+}
 
-    let nb = set_slice(&mut test_init, &exp_ret);
-    assert_eq!(test_init, exp_ret, "{}", SET_ERR);
-    assert_eq!(nb, exp_ret.len(), "{}", NUM_BYTES_ERR);
+#[test]
+fn e2e_book_metadata_test() -> DRes<()> {
+    let (sconf, mut cconf, _cleaner) = e2e_confs("book_meta")?;
+    let sbook = format!("{}/a.pdf", sconf.book_dir);
+    let cbook = format!("{}/a.pdf", cconf.book_dir);
+    write(&sbook, b"%PDF-a")?;
+    write(format!("{}/b.djvu", sconf.book_dir), b"AT&TFORM")?;
+    write(format!("{}/notes.txt", sconf.book_dir), b"notes")?;
+    cconf.book_formats = vec!(DocType::Pdf);
+
+    run_session(sconf, |qrx| {
+        let mut rbuf = [0u8; BLEN];
+        let mut rq = Requester::new(&mut *qrx);
+        rq.handshake(None)?;
+        rq.initialize_files(&cconf)?;
+        rq.get_book(&cconf, "a.pdf")?;
+
+        // unchanged since the listing, nothing is fetched
+        write(&cbook, b"%PDF-a")?;
+        write(&sbook, b"%PDF-changed")?;
+        rq.get_book(&cconf, "a.pdf")?;
+        assert_eq!(read(&cbook)?, b"%PDF-a");
+
+        rq.initialize_files(&cconf)?;
+        rq.get_book(&cconf, "a.pdf")?;
+        assert_eq!(read(&cbook)?, b"%PDF-changed");
+
+        // the server lists every format
+        send_msg(qrx, &Message::GetBookNames)?;
+        let Message::BookNames(books) = recv_msg(qrx, &mut rbuf, &Limits::default())? else {
+            panic!("not a listing");
+        };
+        let types: Vec<DocType> = books.iter().map(|book| book.doc_type).collect();
+        assert_eq!(types, [DocType::Pdf, DocType::Djvu, DocType::Other]);
+        assert_eq!(books[0].size, 12);
+        assert_eq!(books[0].hash, hash_bytes(b"%PDF-changed"));
+        return Ok(());
+    })?;
+
+    let meta = std::fs::metadata(format!("{}/b.djvu", cconf.book_dir));
+    assert!(meta.is_err(), "placeholder for a filtered format");
+    assert!(!Path::new(&format!("{}/notes.txt", cconf.book_dir)).exists());
+
+    return Ok(());
+}
+
+#[test]
+fn book_tree_test() -> DRes<()> {
+    let entry = |path: &str| BookEntry {
+        path: PathBuf::from(path),
+        size: 1,
+        mtime: 0,
+        hash: hash_bytes(path.as_bytes()),
+        doc_type: DocType::Pdf,
+    };
+    let names = |tree: &BookTree, ino: u64| -> Vec<String> {
+        let Some(NodeKind::Dir(children)) = tree.get(ino).map(|node| &node.kind) else {
+            panic!("{ino} is not a directory");
+        };
+        return children.iter()
+            .map(|child| tree.get(*child).unwrap().name.to_string_lossy().into_owned())
+            .collect();
+    };
+
+    let mut tree = BookTree::new();
+    assert_eq!(names(&tree, ROOT_INO), Vec::<String>::new());
+
+    tree.update(&BookIndex { entries: vec!(entry("a.pdf"), entry("a/b.pdf"), entry("a/c/d.pdf")) });
+    assert_eq!(names(&tree, ROOT_INO), ["a", "a.pdf"]);
+    let dir = tree.lookup(ROOT_INO, OsStr::new("a")).unwrap();
+    assert_eq!(names(&tree, dir), ["b.pdf", "c"]);
+    let book = tree.lookup(dir, OsStr::new("b.pdf")).unwrap();
+    assert!(matches!(&tree.get(book).unwrap().kind, NodeKind::Book(e) if e == &entry("a/b.pdf")));
+    assert_eq!(tree.get(book).unwrap().parent, dir);
+    assert_eq!(tree.lookup(book, OsStr::new("x")), None);
+    assert_eq!(tree.lookup(ROOT_INO, OsStr::new("b.pdf")), None);
+
+    // a relisting keeps the inodes of books that are still there
+    let gone = tree.lookup(ROOT_INO, OsStr::new("a.pdf")).unwrap();
+    tree.update(&BookIndex { entries: vec!(entry("a/b.pdf"), entry("e.pdf")) });
+    assert_eq!(tree.lookup(ROOT_INO, OsStr::new("a")), Some(dir));
+    assert_eq!(tree.lookup(dir, OsStr::new("b.pdf")), Some(book));
+    assert_eq!(names(&tree, dir), ["b.pdf"]);
+    assert!(tree.get(gone).is_none());
+    assert_ne!(tree.lookup(ROOT_INO, OsStr::new("e.pdf")), Some(gone));
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_fuse_fetch_test() -> DRes<()> {
+    let (sconf, mut cconf, _cleaner) = e2e_confs("fuse")?;
+    let cache = format!("{}/cache", cconf.book_dir);
+    create_dir_all(&cache)?;
+    cconf.fuse_cache = Some(cache.clone());
+    create_dir_all(format!("{}/sub", sconf.book_dir))?;
+    write(format!("{}/sub/a.pdf", sconf.book_dir), b"%PDF-a")?;
+
+    let (fetcher, queue) = fetch_channel()?;
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        rq.initialize_files(&cconf)?;
+        assert_eq!(rq.index().entries.len(), 1);
+        let mut state_tx = StateFsTx::new(&cconf)?;
+
+        let opener = std::thread::spawn(move || {
+            return [Path::new("sub/a.pdf"), Path::new("missing.pdf")]
+                .map(|path| fetcher.fetch(path));
+        });
+        while !opener.is_finished() {
+            fetch_opened(&queue, &mut rq, &mut state_tx, &cconf)?;
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let res = opener.join().unwrap();
+        assert_eq!(res, [Ok(()), Err(libc::ENOENT)]);
+        return Ok(());
+    })?;
+
+    // the mountpoint never gets placeholders or books
+    assert_eq!(read(format!("{cache}/sub/a.pdf"))?, b"%PDF-a");
+    assert!(!Path::new(&format!("{}/sub", cconf.book_dir)).exists());
+
+    return Ok(());
+}
+
+/// the paths queued within timeout, each fetch is
+/// answered with Ok right away.
+fn fetched(queue: &FetchQueue, timeout: Duration) -> Vec<PathBuf> {
+    let start = std::time::Instant::now();
+    let mut paths = vec!();
+    while start.elapsed() < timeout {
+        for fetch in queue.pending() {
+            let _ = fetch.done.send(Ok(()));
+            paths.push(fetch.path);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    return paths;
+}
+
+#[test]
+fn inotify_open_watch_test() -> DRes<()> {
+    const ROOT: &str = "/tmp/qzb_testing_inotify_opens";
+    let _ = remove_dir_all(ROOT);
+    create_dir_all(ROOT)?;
+    let _cleaner = DirCleaner(ROOT.to_owned());
+    let root = Path::new(ROOT);
+
+    let (fetcher, queue) = fetch_channel()?;
+    let _watcher = OpenWatcher::spawn_inotify(root, fetcher)?;
+
+    // creating placeholders, as the listing does, isn't an open
+    create_dir_all(root.join("sub"))?;
+    std::thread::sleep(Duration::from_millis(50));
+    std::fs::File::create(root.join("sub/a.pdf"))?.set_len(6)?;
+    write(root.join("b.pdf"), b"%PDF-b")?;
+    assert!(is_placeholder(&root.join("sub/a.pdf")));
+    assert!(!is_placeholder(&root.join("b.pdf")));
+    assert_eq!(fetched(&queue, Duration::from_millis(200)), Vec::<PathBuf>::new());
+
+    let _ = read(root.join("sub/a.pdf"))?;
+    let _ = read(root.join("b.pdf"))?;
+    // opened again before the fetch even happened
+    let _ = read(root.join("sub/a.pdf"))?;
+    assert_eq!(fetched(&queue, Duration::from_millis(200)), [PathBuf::from("sub/a.pdf")]);
+
+    return Ok(());
+}
+
+#[test]
+fn fanotify_open_watch_test() -> DRes<()> {
+    const ROOT: &str = "/tmp/qzb_testing_fanotify_opens";
+    let _ = remove_dir_all(ROOT);
+    create_dir_all(format!("{ROOT}/sub"))?;
+    let _cleaner = DirCleaner(ROOT.to_owned());
+    let root = Path::new(ROOT);
+    std::fs::File::create(root.join("sub/a.pdf"))?.set_len(6)?;
+
+    let (fetcher, queue) = fetch_channel()?;
+    let _watcher = match OpenWatcher::spawn_fanotify(root, fetcher) {
+        Ok(watcher) => watcher,
+        // needs CAP_SYS_ADMIN
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
+        Err(e) => Err(e)?,
+    };
+
+    // our own opens go through untouched
+    assert_eq!(read(root.join("sub/a.pdf"))?, [0; 6]);
+
+    let reader = std::thread::spawn(move || {
+        return std::process::Command::new("cat")
+            .arg(format!("{ROOT}/sub/a.pdf"))
+            .output();
+    });
+    let fetch = loop {
+        if let Some(fetch) = queue.pending().pop() {
+            break fetch;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(fetch.path, Path::new("sub/a.pdf"));
+    // the open is held until the book is there
+    write(root.join("sub/a.pdf"), b"%PDF-a")?;
+    let _ = fetch.done.send(Ok(()));
+
+    let output = reader.join().unwrap()?;
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"%PDF-a");
+
+    // a book that is already there isn't fetched again
+    let output = std::process::Command::new("cat")
+        .arg(format!("{ROOT}/sub/a.pdf"))
+        .output()?;
+    assert_eq!(output.stdout, b"%PDF-a");
+    assert!(queue.pending().is_empty());
+
+    return Ok(());
+}
+
+#[test]
+fn notify_roundtrip_test() -> DRes<()> {
+    let msgs = [
+        NotifyMsg::Hello(NOTIFY_VERSION),
+        NotifyMsg::Opened("sub/a.pdf".to_owned()),
+        NotifyMsg::Closed("/home/user/books/a.pdf".to_owned()),
+        NotifyMsg::PageChanged { book: "a.pdf".to_owned(), page: 41 },
+        NotifyMsg::BookmarkAdded { book: "a.pdf".to_owned(), id: "ch2".to_owned(), page: 7 },
+        NotifyMsg::SyncNow,
+        NotifyMsg::Ready("/home/user/books/sub/a.pdf".to_owned()),
+        NotifyMsg::Ok,
+        NotifyMsg::Failed { code: ErrorCode::BookNotFound, reason: "gone".to_owned() },
+    ];
+
+    let mut stream = vec!();
+    for msg in &msgs {
+        let enc = msg.encode()?;
+        assert_eq!(&NotifyMsg::decode(&enc)?, msg);
+        stream.extend(frame(&enc)?);
+    }
+
+    // frames split at any point come out whole
+    let mut buf = vec!();
+    let mut decoded = vec!();
+    for chunk in stream.chunks(5) {
+        buf.extend_from_slice(chunk);
+        while let Some(payload) = take_frame(&mut buf, NOTIFY_FRAME_LEN)? {
+            decoded.push(NotifyMsg::decode(&payload)?);
+        }
+    }
+    assert_eq!(decoded, msgs);
+    assert!(buf.is_empty());
+
+    // a newer hello may carry more than the version
+    let mut newer = NotifyMsg::Hello(NOTIFY_VERSION + 1).encode()?;
+    newer.extend_from_slice(b"extra");
+    assert_eq!(NotifyMsg::decode(&newer)?, NotifyMsg::Hello(NOTIFY_VERSION + 1));
+    assert!(NotifyMsg::decode(&[b'o', 9, 0, 0, 0, b'a']).is_err());
+
+    let mut huge = (NOTIFY_FRAME_LEN + 1).to_le_bytes().to_vec();
+    assert!(matches!(
+        take_frame(&mut huge, NOTIFY_FRAME_LEN),
+        Err(QzbError::LimitExceeded { what: "frame length", .. })));
+
+    return Ok(());
+}
+
+/// a notify socket caller, sends msg and waits for the reply
+fn notify_call(stream: &mut std::os::unix::net::UnixStream, msg: &NotifyMsg) -> DRes<NotifyMsg> {
+    use std::io::{Read, Write};
+    stream.write_all(&frame(&msg.encode()?)?)?;
+
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header)?;
+    let mut payload = vec!(0; u64::from_le_bytes(header).try_into()?);
+    stream.read_exact(&mut payload)?;
+    return NotifyMsg::decode(&payload);
+}
+
+#[test]
+fn e2e_notify_socket_test() -> DRes<()> {
+    use std::{io::{Read, Write}, os::unix::net::UnixStream};
+
+    let (sconf, cconf, _cleaner) = e2e_confs("notify")?;
+    let sock = format!("{}/../notify.sock", cconf.book_dir);
+    create_dir_all(format!("{}/sub", sconf.book_dir))?;
+    write(format!("{}/sub/a.pdf", sconf.book_dir), b"%PDF-a")?;
+    write(format!("{}/b.pdf", sconf.book_dir), b"%PDF-b")?;
+
+    let mut book_tx = BookTx::new(&sock)?;
+    run_session(sconf.clone(), |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        rq.initialize_files(&cconf)?;
+        let mut state_tx = StateFsTx::new(&cconf)?;
+
+        let (book_dir, sock_path) = (cconf.book_dir.clone(), sock.clone());
+        let callers = std::thread::spawn(move || -> DRes<()> {
+            let mut first = UnixStream::connect(&sock_path)?;
+            let mut second = UnixStream::connect(&sock_path)?;
+            let hello = NotifyMsg::Hello(NOTIFY_VERSION);
+            assert_eq!(notify_call(&mut first, &hello)?, hello);
+            assert_eq!(notify_call(&mut second, &hello)?, hello);
+
+            // bare names, relative and absolute paths all work
+            let ready = NotifyMsg::Ready(format!("{book_dir}/sub/a.pdf"));
+            assert_eq!(notify_call(&mut first, &NotifyMsg::Opened("a.pdf".to_owned()))?, ready);
+            let abs = NotifyMsg::Opened(format!("{book_dir}/b.pdf"));
+            assert_eq!(
+                notify_call(&mut second, &abs)?,
+                NotifyMsg::Ready(format!("{book_dir}/b.pdf")));
+            assert!(matches!(
+                notify_call(&mut first, &NotifyMsg::Opened("missing.pdf".to_owned()))?,
+                NotifyMsg::Failed { code: ErrorCode::BookNotFound, .. }));
+            assert!(matches!(
+                notify_call(&mut first, &NotifyMsg::Opened("../etc/passwd".to_owned()))?,
+                NotifyMsg::Failed { code: ErrorCode::PathRejected, .. }));
+
+            let page = NotifyMsg::PageChanged { book: "a.pdf".to_owned(), page: 3 };
+            assert_eq!(notify_call(&mut second, &page)?, NotifyMsg::Ok);
+            assert_eq!(notify_call(&mut first, &NotifyMsg::SyncNow)?, NotifyMsg::Ok);
+            assert_eq!(notify_call(&mut second, &NotifyMsg::Closed("a.pdf".to_owned()))?, NotifyMsg::Ok);
+
+            // a caller of another version only gets our hello
+            let mut old = UnixStream::connect(&sock_path)?;
+            let reply = notify_call(&mut old, &NotifyMsg::Hello(NOTIFY_VERSION + 1))?;
+            assert_eq!(reply, hello);
+            assert_eq!(old.read(&mut [0u8; 1])?, 0);
+
+            // so does a caller skipping the hello or sending garbage
+            let mut rude = UnixStream::connect(&sock_path)?;
+            assert!(matches!(
+                notify_call(&mut rude, &NotifyMsg::SyncNow)?,
+                NotifyMsg::Failed { code: ErrorCode::Protocol, .. }));
+            let mut garbage = UnixStream::connect(&sock_path)?;
+            assert_eq!(notify_call(&mut garbage, &hello)?, hello);
+            garbage.write_all(&frame(b"?")?)?;
+            let mut rest = vec!();
+            let _ = garbage.read_to_end(&mut rest)?;
+            assert!(!rest.is_empty());
+            return Ok(());
+        });
+
+        while !callers.is_finished() {
+            book_tx.handler(&mut rq, &mut state_tx, &cconf)?;
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        return callers.join().unwrap();
+    })?;
+
+    assert_eq!(read(format!("{}/sub/a.pdf", cconf.book_dir))?, b"%PDF-a");
+    assert_eq!(read(format!("{}/b.pdf", cconf.book_dir))?, b"%PDF-b");
+
+    return Ok(());
+}
+
+#[test]
+fn e2e_notify_client_test() -> DRes<()> {
+    let (sconf, cconf, _cleaner) = e2e_confs("notify_client")?;
+    let sock = format!("{}/../notify.sock", cconf.book_dir);
+    create_dir_all(format!("{}/sub", sconf.book_dir))?;
+    write(format!("{}/sub/a.pdf", sconf.book_dir), b"%PDF-a")?;
+
+    let mut book_tx = BookTx::new(&sock)?;
+    run_session(sconf, |qrx| {
+        let mut rq = Requester::new(qrx);
+        rq.handshake(None)?;
+        rq.initialize_files(&cconf)?;
+        let mut state_tx = StateFsTx::new(&cconf)?;
+
+        let sock_path = sock.clone();
+        let caller = std::thread::spawn(move || {
+            let mut client = NotifyClient::connect(&sock_path, Duration::from_secs(10))?;
+            let missing = client.open("missing.pdf");
+            return client.open("a.pdf").map(|path| (path, missing));
+        });
+
+        while !caller.is_finished() {
+            book_tx.handler(&mut rq, &mut state_tx, &cconf)?;
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        let (path, missing) = caller.join().unwrap()?;
+        assert_eq!(path, Path::new(&cconf.book_dir).join("sub/a.pdf"));
+        assert_eq!(read(path)?, b"%PDF-a");
+        assert!(matches!(missing, Err(QzbError::Remote { code: ErrorCode::BookNotFound, .. })));
+        return Ok(());
+    })?;
+
+    return Ok(());
+}
+
+#[test]
+fn event_loop_test() -> DRes<()> {
+    use std::os::{fd::AsRawFd, unix::net::UnixStream};
+
+    let (_, cconf, _cleaner) = e2e_confs("event_loop")?;
+    let sock = format!("{}/../notify.sock", cconf.book_dir);
+    let book_tx = BookTx::new(&sock)?;
+    let watcher = StateWatcher::new(&cconf.state_dir)?;
+    let (fetcher, queue) = fetch_channel()?;
+    let shutdown = ShutdownSignal::new()?;
+
+    let events = EventLoop::new()?;
+    events.add(book_tx.fd(), 0)?;
+    events.add(watcher.fd(), 1)?;
+    events.add(queue.fd(), 2)?;
+    events.add(shutdown.as_raw_fd(), 3)?;
+
+    let mut ready = vec!();
+    let short = Some(Duration::from_millis(50));
+    events.wait(&mut ready, short)?;
+    assert!(ready.is_empty());
+
+    let _caller = UnixStream::connect(&sock)?;
+    events.wait(&mut ready, short)?;
+    assert_eq!(ready, [0]);
+
+    let _fetch = fetcher.request(Path::new("a.pdf")).unwrap();
+    write(format!("{}/history", cconf.state_dir), b"x")?;
+    events.wait(&mut ready, short)?;
+    ready.sort();
+    assert_eq!(ready, [0, 1, 2]);
+
+    // the eventfd is reset along with the queue
+    assert_eq!(queue.pending().len(), 1);
+    assert!(queue.pending().is_empty());
+
+    // the signal is blocked in this thread only, raise
+    // doesn't reach the other test threads.
+    assert_eq!(shutdown.received()?, None);
+    assert_eq!(unsafe { libc::raise(libc::SIGTERM) }, 0);
+    events.wait(&mut ready, short)?;
+    assert!(ready.contains(&3) && !ready.contains(&2));
+    assert_eq!(shutdown.received()?, Some(libc::SIGTERM as u32));
+    assert_eq!(shutdown.sleep(Duration::from_millis(10))?, None);
+
+    drop(book_tx);
+    assert!(!Path::new(&sock).exists());
+    return Ok(());
 }
@@ -0,0 +1,260 @@
+use std::{
+    fs,
+    io::{self, ErrorKind::*},
+    collections::HashMap,
+    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    os::fd::{AsRawFd, RawFd},
+};
+use inotify::{Inotify, WatchMask, WatchDescriptor, EventMask};
+
+/// quiet period that ends a burst of writes, zathura
+/// rewrites its history file several times in a row
+/// when a document is closed.
+const DEBOUNCE: Duration = Duration::from_millis(150);
+/// upper bound on a single burst so a constantly
+/// written file can't starve the rest of the loop.
+const MAX_BURST: Duration = Duration::from_secs(2);
+const EVENT_BUF_LEN: usize = 4096;
+
+const WATCH_MASK: WatchMask = WatchMask::CLOSE_WRITE
+    .union(WatchMask::CREATE)
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::ONLYDIR);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateEvent {
+    /// created, written or moved in from outside the tree
+    Written(PathBuf),
+    /// deleted or moved out of the tree
+    Removed(PathBuf),
+    /// moved within the tree
+    Renamed { from: PathBuf, to: PathBuf },
+}
+
+pub enum WatchEvents {
+    /// changes below the root in the order they happened,
+    /// repeated writes to a path are reported once per burst.
+    Changed(Vec<StateEvent>),
+    /// the kernel queue overflowed and events were
+    /// lost, the caller has to rescan the whole tree.
+    Overflow,
+}
+
+/// recursive inotify watch over a directory tree,
+/// inotify itself only watches a single directory so
+/// every subdirectory gets its own watch descriptor.
+pub struct StateWatcher {
+    inotify: Inotify,
+    wds: HashMap<WatchDescriptor, PathBuf>,
+    buf: [u8; EVENT_BUF_LEN],
+}
+
+impl StateWatcher {
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        let mut watcher = Self {
+            inotify: Inotify::init()?,
+            wds: HashMap::new(),
+            buf: [0u8; EVENT_BUF_LEN],
+        };
+
+        let _ = watcher.add_recursive(root.as_ref())?;
+        return Ok(watcher);
+    }
+
+    pub fn fd(&self) -> RawFd {
+        return self.inotify.as_raw_fd();
+    }
+
+    /// blocks for at most timeout waiting for the first
+    /// event, then collects events until the tree has
+    /// been quiet for DEBOUNCE. An empty Changed means
+    /// the timeout ran out.
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<WatchEvents> {
+        let mut changed = vec!();
+        let mut moved_from = HashMap::new();
+        if !poll_readable(self.fd(), timeout)? {
+            return Ok(WatchEvents::Changed(changed));
+        }
+
+        let burst_start = Instant::now();
+        loop {
+            if self.drain(&mut changed, &mut moved_from)? {
+                return Ok(WatchEvents::Overflow);
+            }
+
+            if burst_start.elapsed() >= MAX_BURST
+                || !poll_readable(self.fd(), DEBOUNCE)? {
+                break;
+            }
+        }
+
+        // the other half of these moves happened outside the tree
+        for (_, from) in moved_from {
+            self.forget_dir(&from);
+            push_event(&mut changed, StateEvent::Removed(from));
+        }
+
+        return Ok(WatchEvents::Changed(changed));
+    }
+
+    /// reads every queued event, returns true on overflow.
+    /// moved_from holds MOVED_FROM paths by cookie until
+    /// their MOVED_TO shows up, possibly in a later call.
+    fn drain(
+        &mut self,
+        changed: &mut Vec<StateEvent>,
+        moved_from: &mut HashMap<u32, PathBuf>,
+    ) -> io::Result<bool> {
+        let mut new_dirs = vec!();
+        let mut renamed_dirs = vec!();
+        loop {
+            let events = match self.inotify.read_events(&mut self.buf) {
+                Ok(events) => events,
+                Err(e) if e.kind() == WouldBlock => break,
+                Err(e) if e.kind() == Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    return Ok(true);
+                }
+
+                if event.mask.contains(EventMask::IGNORED) {
+                    let _ = self.wds.remove(&event.wd);
+                    continue;
+                }
+
+                let (Some(dir), Some(name)) = (self.wds.get(&event.wd), event.name) else {
+                    continue;
+                };
+
+                let path = dir.join(name);
+                let is_dir = event.mask.contains(EventMask::ISDIR);
+
+                if event.mask.contains(EventMask::MOVED_FROM) {
+                    let _ = moved_from.insert(event.cookie, path);
+                } else if event.mask.contains(EventMask::MOVED_TO) {
+                    if let Some(from) = moved_from.remove(&event.cookie) {
+                        if is_dir {
+                            renamed_dirs.push((from.clone(), path.clone()));
+                        }
+                        push_event(changed, StateEvent::Renamed { from, to: path });
+                    } else {
+                        if is_dir {
+                            new_dirs.push(path.clone());
+                        }
+                        push_event(changed, StateEvent::Written(path));
+                    }
+                } else if event.mask.contains(EventMask::DELETE) {
+                    push_event(changed, StateEvent::Removed(path));
+                } else {
+                    if is_dir && event.mask.contains(EventMask::CREATE) {
+                        new_dirs.push(path.clone());
+                    }
+                    push_event(changed, StateEvent::Written(path));
+                }
+            }
+        }
+
+        // a renamed directory keeps its watches, only the
+        // paths we remember for them are stale.
+        for (from, to) in renamed_dirs {
+            for dir in self.wds.values_mut() {
+                if let Ok(rel) = dir.strip_prefix(&from) {
+                    *dir = to.join(rel);
+                }
+            }
+        }
+
+        // files written into a new directory before its
+        // watch existed never produce an event of their own.
+        for dir in new_dirs {
+            for path in self.add_recursive(&dir)? {
+                push_event(changed, StateEvent::Written(path));
+            }
+        }
+
+        return Ok(false);
+    }
+
+    /// also reports writes to files in dir that are still
+    /// open, for files written through a long lived
+    /// descriptor like a database. Not recursive.
+    pub fn watch_modify(&mut self, dir: &Path) -> io::Result<()> {
+        let wd = self.inotify.watches().add(dir, WATCH_MASK.union(WatchMask::MODIFY))?;
+        let _ = self.wds.insert(wd, dir.to_owned());
+        return Ok(());
+    }
+
+    /// drops the watches of a directory that left the tree,
+    /// the kernel keeps watching it wherever it went.
+    fn forget_dir(&mut self, dir: &Path) {
+        let stale: Vec<WatchDescriptor> = self.wds.iter()
+            .filter(|(_, path)| path.starts_with(dir))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+
+        for wd in stale {
+            let _ = self.wds.remove(&wd);
+            let _ = self.inotify.watches().remove(wd);
+        }
+    }
+
+    /// watches dir and every directory below it, returns
+    /// every path found along the way.
+    fn add_recursive(&mut self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut found = vec!();
+        let wd = match self.inotify.watches().add(dir, WATCH_MASK) {
+            Ok(wd) => wd,
+            // removed again before we got to it
+            Err(e) if e.kind() == NotFound => return Ok(found),
+            Err(e) => return Err(e),
+        };
+        let _ = self.wds.insert(wd, dir.to_owned());
+
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                found.extend(self.add_recursive(&path)?);
+            }
+            found.push(path);
+        }
+
+        return Ok(found);
+    }
+}
+
+/// a removal cancels any earlier write to the same path,
+/// repeated writes collapse into the first one.
+fn push_event(events: &mut Vec<StateEvent>, event: StateEvent) {
+    if let StateEvent::Removed(path) = &event {
+        events.retain(|x| *x != StateEvent::Written(path.clone()));
+    }
+
+    if !events.contains(&event) {
+        events.push(event);
+    }
+}
+
+/// returns true if fd became readable before timeout.
+pub fn poll_readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let timeout_ms = timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX);
+
+    // pfd outlives the call and nfds matches its length.
+    let ret = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == Interrupted {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+
+    return Ok(ret > 0);
+}
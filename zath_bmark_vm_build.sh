@@ -17,6 +17,9 @@ ZIP_PATH="$QINC_DIR/qzb.zip";
 PROJ_DIR="$HOME_U/qzb";
 PROJ_MANIFEST="$PROJ_DIR/Cargo.toml";
 AOUT="$PROJ_DIR/target/debug/$PKG_NAME";
+OPEN_AOUT="$PROJ_DIR/target/debug/qzb-open";
+OPEN_INSTALL_PATH="/usr/local/bin/qzb-open";
+DESKTOP_PATH="/usr/local/share/applications/qzb-open.desktop";
 VAULT_RPC_PATH="/etc/qubes-rpc/qubes.ZathuraMgmt";
 # ~~~~~~~~~~~~~~~~ #
 
@@ -34,6 +37,9 @@ chmod 755 $AOUT || exit 4
 
 if [ $IS_SERVER == 1 ]; then 
   mv $AOUT $VAULT_RPC_PATH || exit 7; 
+else
+  install -o root -g root -m 755 $OPEN_AOUT $OPEN_INSTALL_PATH || exit 10;
+  install -D -m 644 $PROJ_DIR/qzb-open.desktop $DESKTOP_PATH || exit 11;
 fi
 
 rm -f $SELF_PATH || exit 9;